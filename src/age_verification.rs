@@ -0,0 +1,424 @@
+use crate::MDBResponse;
+use crate::Mdb;
+
+use defmt::Format;
+use embedded_hal::delay::DelayNs;
+
+//Age verification device - checks the customer's age (eg from an ID card or app) before
+//an age-restricted selection is sold.  By default a pass is good for one selection.  The
+//application can set verification_valid_ms so a customer buying several restricted items
+//only has to verify once - CreditManager clears the pass when that customer is done, so
+//it can't be used by the next one.
+//
+//Which selections are restricted is up to the application - see AgeRestrictions, and
+//CreditManager::request_vend, which won't sell a restricted selection for cash or cashless
+//without a pass.
+
+pub const AGE_VERIFICATION_ADDRESS: u8 = 0x68;
+
+const RESET_CMD: u8 = 0x68;
+const SETUP_CMD: u8 = 0x69;
+const POLL_CMD: u8 = 0x6A;
+const CONTROL_CMD: u8 = 0x6B;
+const VERIFY_CMD: u8 = 0x6C;
+const EXPANSION_CMD: u8 = 0x6F;
+
+const CONTROL_DISABLE: u8 = 0x00;
+const CONTROL_ENABLE: u8 = 0x01;
+const CONTROL_CANCEL: u8 = 0x02;
+
+const EXPANSION_REQUEST_ID: u8 = 0x00;
+
+const POLL_REPLY_JUST_RESET: u8 = 0x00;
+const POLL_REPLY_PASSED: u8 = 0x01;
+const POLL_REPLY_FAILED: u8 = 0x02;
+const POLL_REPLY_IN_PROGRESS: u8 = 0x03;
+const POLL_REPLY_MALFUNCTION: u8 = 0x04;
+const POLL_REPLY_PERIPHERAL_ID: u8 = 0x09;
+
+//Setup option bits
+const OPTION_REPORTS_AGE: u8 = 0x01;
+
+//Age reported when the device only says pass/fail
+const AGE_UNKNOWN: u8 = 0xFF;
+
+//A pass is only good for the selection it was given for
+const DEFAULT_VERIFICATION_VALID_MS: u32 = 0;
+
+//Most selections the application can restrict
+pub const MAX_RESTRICTED_SELECTIONS: usize = 32;
+
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum AgeVerificationFailure {
+    Underage,
+    NoIdPresented,
+    InvalidDocument,
+    Cancelled,
+    Other(u8),
+}
+
+impl AgeVerificationFailure {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => AgeVerificationFailure::Underage,
+            0x02 => AgeVerificationFailure::NoIdPresented,
+            0x03 => AgeVerificationFailure::InvalidDocument,
+            0x04 => AgeVerificationFailure::Cancelled,
+            other => AgeVerificationFailure::Other(other),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Format)]
+pub enum AgeVerificationPollEvent {
+    JustReset,
+    //The device reset unexpectedly, and we have re-initialised it (true if successful).
+    //Poll returns this in place of JustReset.
+    Reinitialised(bool),
+    //Age, if the device reports it
+    Passed(Option<u8>),
+    Failed(AgeVerificationFailure),
+    InProgress,
+    Malfunction(u8),
+    Unhandled(u8),
+}
+
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum VerificationOutcome {
+    Passed(Option<u8>),
+    Failed(AgeVerificationFailure),
+    TimedOut,
+}
+
+/// The minimum age for each age-restricted selection
+#[derive(Copy, Clone, Format)]
+pub struct AgeRestrictions {
+    selections: [Option<([u8; 2], u8)>; MAX_RESTRICTED_SELECTIONS],
+}
+
+impl AgeRestrictions {
+    pub fn new() -> Self {
+        Self {
+            selections: [None; MAX_RESTRICTED_SELECTIONS],
+        }
+    }
+
+    /// Restrict a selection to customers of at least minimum_age.  Returns false if
+    /// there is no room for it.
+    pub fn restrict(&mut self, address: [u8; 2], minimum_age: u8) -> bool {
+        let slot = match self
+            .selections
+            .iter()
+            .position(|s| s.is_some_and(|(a, _)| a == address))
+        {
+            Some(i) => Some(i),
+            None => self.selections.iter().position(|s| s.is_none()),
+        };
+        match slot {
+            Some(i) => {
+                self.selections[i] = Some((address, minimum_age));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn unrestrict(&mut self, address: [u8; 2]) {
+        for s in self.selections.iter_mut() {
+            if s.is_some_and(|(a, _)| a == address) {
+                *s = None;
+            }
+        }
+    }
+
+    /// Minimum age for a selection, or None if it isn't restricted
+    pub fn minimum_age(&self, address: [u8; 2]) -> Option<u8> {
+        self.selections
+            .iter()
+            .flatten()
+            .find(|(a, _)| *a == address)
+            .map(|(_, age)| *age)
+    }
+}
+
+impl Default for AgeRestrictions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Copy, Clone, Format)]
+struct VerifiedAge {
+    age: Option<u8>,
+    minimum_age: u8,
+    at: u64, //Timer counter, uS
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct AgeVerificationDevice {
+    pub feature_level: u8,
+    pub max_response_time: u8, //Seconds
+    //The device tells us the customer's age, rather than just pass/fail
+    pub reports_age: bool,
+    pub manufacturer_code: [u8; 3],
+    pub serial_number: [u8; 12],
+    pub model_number: [u8; 12],
+    pub software_version: [u8; 2],
+    //How long a pass lasts - 0 means it is used up by the selection it was given for
+    pub verification_valid_ms: u32,
+    pub enabled: bool,
+    verified: Option<VerifiedAge>,
+}
+
+impl AgeVerificationDevice {
+    pub fn init<T: embedded_io::Write + embedded_io::Read>(bus: &mut Mdb<T>) -> Option<Self> {
+        bus.send_data(&[RESET_CMD]);
+        bus.timer.delay_ms(100);
+
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[POLL_CMD]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(_) if buf[0] == POLL_REPLY_JUST_RESET => {}
+            _ => {
+                defmt::debug!("No reset reply from age verification device");
+                return None;
+            }
+        }
+
+        let mut device = Self::setup(bus)?;
+        device.identify(bus);
+        device.set_enabled(bus, true);
+        Some(device)
+    }
+
+    fn setup<T: embedded_io::Write + embedded_io::Read>(bus: &mut Mdb<T>) -> Option<Self> {
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[SETUP_CMD]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count >= 3 => Some(Self {
+                feature_level: buf[0],
+                max_response_time: buf[1],
+                reports_age: buf[2] & OPTION_REPORTS_AGE != 0,
+                manufacturer_code: [0x00; 3],
+                serial_number: [0x00; 12],
+                model_number: [0x00; 12],
+                software_version: [0x00; 2],
+                verification_valid_ms: DEFAULT_VERIFICATION_VALID_MS,
+                enabled: false,
+                verified: None,
+            }),
+            _ => {
+                defmt::debug!("Invalid setup reply from age verification device");
+                None
+            }
+        }
+    }
+
+    fn reinitialise<T: embedded_io::Write + embedded_io::Read>(&mut self, bus: &mut Mdb<T>) -> bool {
+        defmt::info!("Age verification device reset unexpectedly - re-initialising");
+        match Self::setup(bus) {
+            Some(mut device) => {
+                device.manufacturer_code = self.manufacturer_code;
+                device.serial_number = self.serial_number;
+                device.model_number = self.model_number;
+                device.software_version = self.software_version;
+                device.verification_valid_ms = self.verification_valid_ms;
+                let enabled = self.enabled;
+                //Any verification in progress is lost, but a pass already given stands
+                device.verified = self.verified;
+                *self = device;
+                self.set_enabled(bus, enabled)
+            }
+            None => {
+                defmt::error!("Age verification device failed to re-initialise");
+                false
+            }
+        }
+    }
+
+    fn identify<T: embedded_io::Write + embedded_io::Read>(&mut self, bus: &mut Mdb<T>) -> bool {
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[EXPANSION_CMD, EXPANSION_REQUEST_ID]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count >= 30 && buf[0] == POLL_REPLY_PERIPHERAL_ID => {
+                self.manufacturer_code.copy_from_slice(&buf[1..4]);
+                self.serial_number.copy_from_slice(&buf[4..16]);
+                self.model_number.copy_from_slice(&buf[16..28]);
+                self.software_version.copy_from_slice(&buf[28..30]);
+                true
+            }
+            _ => {
+                defmt::debug!("Age verification device did not identify itself");
+                false
+            }
+        }
+    }
+
+    pub fn set_enabled<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        enable: bool,
+    ) -> bool {
+        self.enabled = enable;
+        let sub = if enable { CONTROL_ENABLE } else { CONTROL_DISABLE };
+        bus.send_data_and_confirm_ack(&[CONTROL_CMD, sub])
+    }
+
+    pub fn poll<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> [Option<AgeVerificationPollEvent>; 4] {
+        let mut poll_results: [Option<AgeVerificationPollEvent>; 4] = [None; 4];
+        let mut result_count: usize = 0;
+
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[POLL_CMD]);
+        if let MDBResponse::Data(count) = bus.receive_response(&mut buf) {
+            let mut index = 0;
+            while index < count && result_count < poll_results.len() {
+                let next = if index + 1 < count { buf[index + 1] } else { 0x00 };
+                let event = match buf[index] {
+                    POLL_REPLY_JUST_RESET => (AgeVerificationPollEvent::JustReset, 1),
+                    POLL_REPLY_PASSED => {
+                        let age = if !self.reports_age || next == AGE_UNKNOWN {
+                            None
+                        } else {
+                            Some(next)
+                        };
+                        (AgeVerificationPollEvent::Passed(age), 2)
+                    }
+                    POLL_REPLY_FAILED => (
+                        AgeVerificationPollEvent::Failed(AgeVerificationFailure::from_code(next)),
+                        2,
+                    ),
+                    POLL_REPLY_IN_PROGRESS => (AgeVerificationPollEvent::InProgress, 1),
+                    POLL_REPLY_MALFUNCTION => {
+                        (AgeVerificationPollEvent::Malfunction(next), 2)
+                    }
+                    other => {
+                        defmt::debug!(
+                            "Unhandled poll reply from age verification device: {=[u8]:#04x}",
+                            buf[index..count]
+                        );
+                        //Don't know how long it is, so give up on the rest
+                        (AgeVerificationPollEvent::Unhandled(other), count - index)
+                    }
+                };
+                poll_results[result_count] = Some(event.0);
+                result_count += 1;
+                index += event.1;
+            }
+        }
+
+        for result in poll_results.iter_mut() {
+            if matches!(result, Some(AgeVerificationPollEvent::JustReset)) {
+                *result = Some(AgeVerificationPollEvent::Reinitialised(self.reinitialise(bus)));
+            }
+        }
+        poll_results
+    }
+
+    /// Ask the device to verify the customer is at least minimum_age, without waiting.
+    /// The result comes back from poll.
+    pub fn request_verification<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        minimum_age: u8,
+    ) -> bool {
+        bus.send_data_and_confirm_ack(&[VERIFY_CMD, minimum_age])
+    }
+
+    pub fn cancel_verification<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+    ) -> bool {
+        bus.send_data_and_confirm_ack(&[CONTROL_CMD, CONTROL_CANCEL])
+    }
+
+    /// Verify the customer is at least minimum_age, waiting up to 30 seconds for them
+    /// to present their ID.  A pass is remembered - see is_verified.
+    pub fn verify<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        minimum_age: u8,
+    ) -> VerificationOutcome {
+        if !self.request_verification(bus, minimum_age) {
+            defmt::error!("Age verification device did not accept verification request");
+            return VerificationOutcome::Failed(AgeVerificationFailure::Other(0x00));
+        }
+        for _ in 0..150 {
+            for event in self.poll(bus).into_iter().flatten() {
+                match event {
+                    AgeVerificationPollEvent::Passed(age) => {
+                        defmt::debug!("Age verification passed");
+                        self.verified = Some(VerifiedAge {
+                            age,
+                            minimum_age,
+                            at: bus.timer.get_counter().ticks(),
+                        });
+                        return VerificationOutcome::Passed(age);
+                    }
+                    AgeVerificationPollEvent::Failed(reason) => {
+                        defmt::info!("Age verification failed - {}", reason);
+                        return VerificationOutcome::Failed(reason);
+                    }
+                    AgeVerificationPollEvent::Reinitialised(_) => {
+                        return VerificationOutcome::Failed(AgeVerificationFailure::Other(0x00));
+                    }
+                    _ => {}
+                }
+            }
+            bus.timer.delay_ms(200);
+        }
+        self.cancel_verification(bus);
+        VerificationOutcome::TimedOut
+    }
+
+    /// Whether the customer has passed verification for minimum_age recently enough
+    pub fn is_verified<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        minimum_age: u8,
+    ) -> bool {
+        let verified = match self.verified {
+            Some(verified) => verified,
+            None => return false,
+        };
+        let elapsed_us = bus.timer.get_counter().ticks().saturating_sub(verified.at);
+        if elapsed_us >= self.verification_valid_ms as u64 * 1000 {
+            return false;
+        }
+        match verified.age {
+            Some(age) => age >= minimum_age,
+            None => verified.minimum_age >= minimum_age,
+        }
+    }
+
+    /// Forget any pass - eg when the customer's session ends
+    pub fn clear_verification(&mut self) {
+        self.verified = None;
+    }
+
+    /// Make sure the customer may buy a selection - verifies them if it is restricted,
+    /// and they haven't already passed.
+    pub fn authorise_selection<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        restrictions: &AgeRestrictions,
+        address: [u8; 2],
+    ) -> bool {
+        let minimum_age = match restrictions.minimum_age(address) {
+            Some(age) => age,
+            None => return true,
+        };
+        if self.is_verified(bus, minimum_age) {
+            return true;
+        }
+        let passed = matches!(self.verify(bus, minimum_age), VerificationOutcome::Passed(_));
+        if self.verification_valid_ms == 0 {
+            //Single use - it has been used for this selection
+            self.clear_verification();
+        }
+        passed
+    }
+}
@@ -0,0 +1,468 @@
+use crate::cashless_device::CashlessDevice;
+use crate::coin_acceptor::{CoinAcceptor, CoinRouting, PollEvent};
+use crate::money::Money;
+
+use core::fmt::Write;
+use defmt::Format;
+
+//EVA-DTS audit data, collected from the coin acceptor and cashless device as the machine
+//runs, and rendered as a DEX/UCS file for the operator.  Counters are kept both since
+//initialisation (never reset) and since the last reset (cleared by reset_interim once
+//the operator has read the file).  All values are unscaled, ie in the smallest currency unit.
+
+//How many selections we keep PA records for
+pub const MAX_AUDIT_SELECTIONS: usize = 64;
+
+//Communication ID for the DXS segment
+const DEX_COMMS_ID: &str = "MDB0000000";
+
+/// A count of events, and their total value, since initialisation and since last reset
+#[derive(Copy, Clone, Format, Default)]
+pub struct AuditCounter {
+    pub count: u32,
+    pub value: u32,
+    pub interim_count: u32,
+    pub interim_value: u32,
+}
+
+impl AuditCounter {
+    pub fn add(&mut self, count: u32, value: u32) {
+        self.count = self.count.saturating_add(count);
+        self.value = self.value.saturating_add(value);
+        self.interim_count = self.interim_count.saturating_add(count);
+        self.interim_value = self.interim_value.saturating_add(value);
+    }
+
+    fn reset_interim(&mut self) {
+        self.interim_count = 0;
+        self.interim_value = 0;
+    }
+}
+
+/// PA record for a selection
+#[derive(Copy, Clone, Format)]
+pub struct SelectionAudit {
+    pub address: [u8; 2], //As used in the MDB vend commands
+    pub price: u32,
+    pub vends: AuditCounter,
+}
+
+/// Per coin type counts for the CA17 records
+#[derive(Copy, Clone, Format, Default)]
+pub struct CoinTypeAudit {
+    pub value: u32,
+    pub in_tube: u8,
+    pub filled: u32,
+    pub dispensed: u32,
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct Audit {
+    pub machine_serial: [u8; 12],
+    pub machine_model: [u8; 12],
+
+    //CA - cash
+    pub cash_sales: AuditCounter,
+    pub coins_to_cashbox: AuditCounter,
+    pub coins_to_tubes: AuditCounter,
+    pub bills_in: AuditCounter,
+    pub coins_paid_out: AuditCounter,
+    pub coins_manually_dispensed: AuditCounter,
+    pub coins_filled: AuditCounter,
+    pub coin_types: [Option<CoinTypeAudit>; 16],
+
+    //DA - cashless
+    pub cashless_sales: AuditCounter,
+
+    //PA - per selection
+    pub selections: [Option<SelectionAudit>; MAX_AUDIT_SELECTIONS],
+}
+
+impl Audit {
+    pub fn new(machine_serial: [u8; 12], machine_model: [u8; 12]) -> Self {
+        Self {
+            machine_serial,
+            machine_model,
+            cash_sales: AuditCounter::default(),
+            coins_to_cashbox: AuditCounter::default(),
+            coins_to_tubes: AuditCounter::default(),
+            bills_in: AuditCounter::default(),
+            coins_paid_out: AuditCounter::default(),
+            coins_manually_dispensed: AuditCounter::default(),
+            coins_filled: AuditCounter::default(),
+            coin_types: [None; 16],
+            cashless_sales: AuditCounter::default(),
+            selections: [None; MAX_AUDIT_SELECTIONS],
+        }
+    }
+
+    /// Account for a coin acceptor poll event
+    pub fn record_coin_event(&mut self, event: &PollEvent) {
+        match event {
+            PollEvent::Coin(coin) => match coin.routing {
+                CoinRouting::CashBox => self.coins_to_cashbox.add(1, coin.value.minor_units),
+                CoinRouting::Tube => {
+                    self.coins_to_tubes.add(1, coin.value.minor_units);
+                    if let Some(ct) = self.coin_types[coin.coin_type as usize].as_mut() {
+                        ct.in_tube = coin.coins_remaining;
+                    }
+                }
+                _ => {}
+            },
+            PollEvent::ManualDispense(dispense) => {
+                self.coins_manually_dispensed.add(
+                    dispense.number as u32,
+                    dispense.value.minor_units * dispense.number as u32,
+                );
+                if let Some(ct) = self.coin_types[dispense.coin_type as usize].as_mut() {
+                    ct.dispensed = ct.dispensed.saturating_add(dispense.number as u32);
+                    ct.in_tube = dispense.coins_remaining;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Change paid out by CoinAcceptor::payout
+    pub fn record_payout(&mut self, amount: Money) {
+        self.coins_paid_out.add(1, amount.minor_units);
+    }
+
+    pub fn record_bill(&mut self, value: Money) {
+        self.bills_in.add(1, value.minor_units);
+    }
+
+    /// Coins put into the tubes by hand, as given by CoinAcceptor::controlled_manual_fill_report
+    pub fn record_fill(&mut self, acceptor: &CoinAcceptor, report: &[u8; 16]) {
+        for (i, count) in report.iter().enumerate() {
+            if let Some(coin) = acceptor.coin_types[i] {
+                self.coins_filled
+                    .add(*count as u32, coin.value.minor_units * *count as u32);
+                if let Some(ct) = self.coin_types[i].as_mut() {
+                    ct.filled = ct.filled.saturating_add(*count as u32);
+                }
+            }
+        }
+        self.update_tubes(acceptor);
+    }
+
+    /// Take the current tube contents from the coin acceptor
+    pub fn update_tubes(&mut self, acceptor: &CoinAcceptor) {
+        for (i, c) in acceptor.coin_types.iter().enumerate() {
+            match c {
+                Some(coin) => {
+                    let ct = self.coin_types[i].get_or_insert(CoinTypeAudit::default());
+                    ct.value = coin.value.minor_units;
+                    ct.in_tube = coin.num_coins;
+                }
+                None => self.coin_types[i] = None,
+            }
+        }
+    }
+
+    pub fn record_cash_vend(&mut self, address: [u8; 2], price: Money) {
+        self.cash_sales.add(1, price.minor_units);
+        self.record_selection(address, price.minor_units);
+    }
+
+    /// A vend paid for by the card reader, ie after CashlessDevice::vend_success
+    pub fn record_cashless_vend(&mut self, address: [u8; 2], price: Money) {
+        self.cashless_sales.add(1, price.minor_units);
+        self.record_selection(address, price.minor_units);
+    }
+
+    fn record_selection(&mut self, address: [u8; 2], price: u32) {
+        let slot = match self
+            .selections
+            .iter()
+            .position(|s| s.is_some_and(|s| s.address == address))
+        {
+            Some(i) => Some(i),
+            None => self.selections.iter().position(|s| s.is_none()),
+        };
+        match slot {
+            Some(i) => {
+                let selection = self.selections[i].get_or_insert(SelectionAudit {
+                    address,
+                    price,
+                    vends: AuditCounter::default(),
+                });
+                selection.price = price;
+                selection.vends.add(1, price);
+            }
+            None => defmt::error!("No room to audit selection {=[u8]:#04x}", address),
+        }
+    }
+
+    /// The operator has collected the audit - clear the since last reset counters
+    pub fn reset_interim(&mut self) {
+        self.cash_sales.reset_interim();
+        self.coins_to_cashbox.reset_interim();
+        self.coins_to_tubes.reset_interim();
+        self.bills_in.reset_interim();
+        self.coins_paid_out.reset_interim();
+        self.coins_manually_dispensed.reset_interim();
+        self.coins_filled.reset_interim();
+        self.cashless_sales.reset_interim();
+        for selection in self.selections.iter_mut().flatten() {
+            selection.vends.reset_interim();
+        }
+    }
+
+    /// Write the audit as a DEX/UCS file into buf, returning its' length, or None
+    /// if buf is too small.
+    pub fn render_dex(
+        &self,
+        acceptor: Option<&CoinAcceptor>,
+        cashless: Option<&CashlessDevice>,
+        buf: &mut [u8],
+    ) -> Option<usize> {
+        let mut w = DexWriter {
+            buf,
+            len: 0,
+            crc: 0x0000,
+            in_transaction: false,
+            segments: 0,
+        };
+
+        w.segment(format_args!("DXS*{}*VA*V0/6*1", DEX_COMMS_ID))?;
+        w.in_transaction = true;
+        w.segment(format_args!("ST*001*0001"))?;
+        w.segment(format_args!(
+            "ID1*{}*{}*0",
+            ascii(&self.machine_serial),
+            ascii(&self.machine_model)
+        ))?;
+
+        //Cash
+        if let Some(acceptor) = acceptor {
+            match &acceptor.l3_features {
+                Some(l3) => w.segment(format_args!(
+                    "CA1*{}*{}*{:02X}{:02X}",
+                    ascii(&l3.serial_number),
+                    ascii(&l3.model),
+                    l3.software_ver[0],
+                    l3.software_ver[1]
+                ))?,
+                None => w.segment(format_args!("CA1***"))?,
+            }
+        }
+        w.segment(format_args!(
+            "CA2*{}*{}*{}*{}",
+            self.cash_sales.value,
+            self.cash_sales.count,
+            self.cash_sales.interim_value,
+            self.cash_sales.interim_count
+        ))?;
+        w.segment(format_args!(
+            "CA3*{}*{}*{}*{}*{}*{}*{}*{}",
+            self.coins_to_cashbox
+                .interim_value
+                .saturating_add(self.coins_to_tubes.interim_value)
+                .saturating_add(self.bills_in.interim_value),
+            self.coins_to_cashbox.interim_value,
+            self.coins_to_tubes.interim_value,
+            self.bills_in.interim_value,
+            self.coins_to_cashbox
+                .value
+                .saturating_add(self.coins_to_tubes.value)
+                .saturating_add(self.bills_in.value),
+            self.coins_to_cashbox.value,
+            self.coins_to_tubes.value,
+            self.bills_in.value
+        ))?;
+        w.segment(format_args!(
+            "CA4*{}*{}*{}*{}",
+            self.coins_paid_out.interim_value,
+            self.coins_manually_dispensed.interim_value,
+            self.coins_paid_out.value,
+            self.coins_manually_dispensed.value
+        ))?;
+        w.segment(format_args!(
+            "CA10*{}*{}",
+            self.coins_filled.interim_value, self.coins_filled.value
+        ))?;
+        let tube_value: u32 = self.coin_types.iter().flatten().fold(0, |total: u32, ct| {
+            total.saturating_add(ct.value.saturating_mul(ct.in_tube as u32))
+        });
+        w.segment(format_args!("CA15*{}", tube_value))?;
+        for (i, ct) in self.coin_types.iter().enumerate() {
+            if let Some(ct) = ct {
+                w.segment(format_args!(
+                    "CA17*{:02}*{}*{}*{}*{}",
+                    i, ct.value, ct.in_tube, ct.filled, ct.dispensed
+                ))?;
+            }
+        }
+
+        //Cashless
+        if let Some(device) = cashless {
+            w.segment(format_args!(
+                "DA1*{}*{}*{:02X}{:02X}",
+                ascii(&device.serial_number),
+                ascii(&device.model_number),
+                device.software_version[0],
+                device.software_version[1]
+            ))?;
+            w.segment(format_args!(
+                "DA2*{}*{}*{}*{}",
+                self.cashless_sales.value,
+                self.cashless_sales.count,
+                self.cashless_sales.interim_value,
+                self.cashless_sales.interim_count
+            ))?;
+        }
+
+        //Per selection
+        for selection in self.selections.iter().flatten() {
+            w.segment(format_args!(
+                "PA1*{}*{}",
+                u16::from_be_bytes(selection.address),
+                selection.price
+            ))?;
+            w.segment(format_args!(
+                "PA2*{}*{}*{}*{}",
+                selection.vends.count,
+                selection.vends.value,
+                selection.vends.interim_count,
+                selection.vends.interim_value
+            ))?;
+        }
+
+        //The CRC covers everything from ST up to here
+        let crc = w.crc;
+        w.segment(format_args!("G85*{:04X}", crc))?;
+        let segments = w.segments + 1;
+        w.segment(format_args!("SE*{}*0001", segments))?;
+        w.in_transaction = false;
+        w.segment(format_args!("DXE*1*1"))?;
+
+        Some(w.len)
+    }
+}
+
+//Fields from the devices are ASCII, but don't trust them
+fn ascii(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("").trim_end_matches(['\0', ' '])
+}
+
+/// CRC-16 as used for the DEX G85 segment (polynomial 0x8005, reflected, initial value 0)
+pub fn dex_crc16(crc: u16, byte: u8) -> u16 {
+    let mut crc = crc ^ byte as u16;
+    for _ in 0..8 {
+        if crc & 0x0001 != 0 {
+            crc = (crc >> 1) ^ 0xA001;
+        } else {
+            crc >>= 1;
+        }
+    }
+    crc
+}
+
+struct DexWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    crc: u16,
+    //Between ST and SE - segments are counted, and included in the CRC
+    in_transaction: bool,
+    segments: u16,
+}
+
+impl DexWriter<'_> {
+    fn segment(&mut self, args: core::fmt::Arguments) -> Option<()> {
+        if self.write_fmt(args).is_err() || self.write_str("\r\n").is_err() {
+            defmt::error!("Buffer too small for DEX file");
+            return None;
+        }
+        if self.in_transaction {
+            self.segments += 1;
+        }
+        Some(())
+    }
+}
+
+impl Write for DexWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let bytes = s.as_bytes();
+        if self.len + bytes.len() > self.buf.len() {
+            return Err(core::fmt::Error);
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        if self.in_transaction {
+            for byte in bytes {
+                self.crc = dex_crc16(self.crc, *byte);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn crc_check_value() {
+        let crc = b"123456789".iter().fold(0, |crc, b| dex_crc16(crc, *b));
+        assert_eq!(crc, 0xBB3D);
+    }
+
+    #[test]
+    fn render_known_audit() {
+        let mut audit = Audit::new(*b"SERIAL000001", *b"MODEL       ");
+        audit.cash_sales.add(2, 250);
+        audit.coins_to_cashbox.add(1, u32::MAX);
+        audit.coins_to_tubes.add(3, 60);
+        audit.coin_types[2] = Some(CoinTypeAudit {
+            value: 20,
+            in_tube: 3,
+            filled: 0,
+            dispensed: 0,
+        });
+        audit.record_selection([0x00, 0x05], 125);
+        audit.record_selection([0x00, 0x05], 125);
+
+        let mut buf = [0u8; 1024];
+        let len = audit.render_dex(None, None, &mut buf).unwrap();
+        let text = core::str::from_utf8(&buf[..len]).unwrap();
+        let lines: Vec<&str> = text.split_terminator("\r\n").collect();
+
+        let expected_start = [
+            "DXS*MDB0000000*VA*V0/6*1",
+            "ST*001*0001",
+            "ID1*SERIAL000001*MODEL*0",
+            "CA2*250*2*250*2",
+            "CA3*4294967295*4294967295*60*0*4294967295*4294967295*60*0",
+            "CA4*0*0*0*0",
+            "CA10*0*0",
+            "CA15*60",
+            "CA17*02*20*3*0*0",
+            "PA1*5*125",
+            "PA2*2*250*2*250",
+        ];
+        assert_eq!(lines[..expected_start.len()], expected_start);
+
+        //CRC runs from the start of ST up to the G85 segment
+        let st = text.find("ST*").unwrap();
+        let g85 = text.find("G85*").unwrap();
+        let crc = text.as_bytes()[st..g85]
+            .iter()
+            .fold(0, |crc, b| dex_crc16(crc, *b));
+        let n = expected_start.len();
+        assert_eq!(lines[n], std::format!("G85*{:04X}", crc));
+        //SE counts the segments from ST to SE, both included
+        assert_eq!(lines[n + 1], std::format!("SE*{}*0001", n + 1));
+        assert_eq!(lines[n + 2], "DXE*1*1");
+        assert_eq!(lines.len(), n + 3);
+    }
+
+    #[test]
+    fn render_into_small_buffer() {
+        let audit = Audit::new([0; 12], [0; 12]);
+        let mut buf = [0u8; 32];
+        assert_eq!(audit.render_dex(None, None, &mut buf), None);
+    }
+}
@@ -1,3 +1,8 @@
+use crate::currency;
+use crate::currency::Currency;
+use crate::ftl;
+use crate::ftl::{FtlDevice, FtlReply};
+use crate::money::{Money, WireFormat};
 use crate::MDBResponse;
 use crate::MDBStatus;
 use crate::Mdb;
@@ -6,12 +11,14 @@ use defmt::Format;
 use embedded_hal::delay::DelayNs;
 use enumn::N;
 
+//Our address on the bus
+const CASHLESS_ADDRESS: u8 = 0x10;
+
 const RESET: u8 = 0x10;
 
 const SETUP_PREFIX: u8 = 0x11;
 const SETUP_CONFIG_DATA: u8 = 0x00;
 const SETUP_MAX_MIN_PRICES: u8 = 0x01;
-const SETUP_REPLY_READER_CONFIG_DATA: u8 = 0x01;
 
 const POLL_CMD: u8 = 0x12;
 //Various poll replies
@@ -31,11 +38,9 @@ const POLL_REPLY_OUT_OF_SEQUENCE: u8 = 0x0B;
 const POLL_REPLY_REVALUE_APPROVED: u8 = 0x0D;
 const POLL_REPLY_REVALUE_DENIED: u8 = 0x0E;
 const POLL_REPLY_REVALUE_LIMIT_AMOUNT: u8 = 0x0F;
-const POLL_REPLY_USER_FILE_DATA: u8 = 0x10;
 const POLL_REPLY_TIME_DATE_REQUEST: u8 = 0x11;
 const POLL_REPLY_DATA_ENTRY_REQUEST: u8 = 0x12;
-const POLL_REQUEST_DATA_ENTRY_CANCEL: u8 = 0x13;
-//We do not support FTL
+//FTL replies (0x1B-0x1F) are handled by the ftl module
 const POLL_REPLY_DIAGNOSTICS: u8 = 0xFF;
 
 //Vend commands
@@ -46,44 +51,147 @@ const VEND_SUCCESS: u8 = 0x02;
 const VEND_FAILURE: u8 = 0x03;
 const VEND_SESSION_COMPLETE: u8 = 0x04;
 const VEND_CASH_SALE: u8 = 0x05;
-const NEGATIVE_VEND_REQUEST: u8 = 0x06;
-//Vend replies
-const VEND_REPLY_APPROVED: u8 = 0x05;
-const VEND_REPLY_DENIED: u8 = 0x06;
-const VEND_REPLY_END_SESSION: u8 = 0x07;
-const VEND_REPLY_CANCELLED: u8 = 0x08;
 
 //Vend reader commands
 const VEND_READER_PREFIX: u8 = 0x14;
 const VEND_READER_DISABLE: u8 = 0x00;
 const VEND_READER_ENABLE: u8 = 0x01;
-const VEND_READER_CANCEL: u8 = 0x02;
-const VEND_READER_DATA_ENTRY_RESP: u8 = 0x03;
-
-//Vend revalue commands
-const VEND_REVALUE_PREFIX: u8 = 0x15;
-const VEND_REVALUE_REQUEST: u8 = 0x00;
-const VEND_REVALUE_LIMIT_REQUEST: u8 = 0x01;
-//Vend revalue replies
-const VEND_REPLY_REVALUE_APPROVED: u8 = 0x0D;
-const VEND_REPLY_REVALUE_DENIED: u8 = 0x0E;
-const VEND_REPLY_REVALUE_LIMIT_AMOUNT: u8 = 0x0F;
-
-//Some multi byte pre-written message to send to device
-//Breakdown - VMC level 3, display with no rows, no columns (none which we will
-//share with the contactless device, anyway!)
-const VMC_SETUP_DATA: [u8; 6] = [0x11, 0x00, 0x03, 0x00, 0x00, 0x00];
-
-//Max and min prices set as "dont know"
-const VMC_MAX_MIN_PRICE_DATA: [u8; 6] = [0x11, 0x01, 0xFF, 0xFF, 0x00, 0x00];
-
-//This is how we identify ourself to the cashless device
-const VMC_EXPANSION_REQUEST_ID_DATA: [u8; 31] = [
-    0x17, 0x00, b'D', b'M', b'P', //Manufacturer ID
-    b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'1', //Serial number
-    b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'1', //Model number
-    b'0', b'1', //Software version
-];
+
+//Expansion commands
+const EXPANSION_PREFIX: u8 = 0x17;
+const EXPANSION_REQUEST_ID: u8 = 0x00;
+const EXPANSION_ENABLE_OPTIONS: u8 = 0x04;
+const EXPANSION_DIAGNOSTICS: u8 = 0xFF;
+
+//Longest diagnostics payload that fits in a message alongside the prefix, command and checksum
+const MAX_DIAGNOSTICS_PAYLOAD: usize = 33;
+//Longest diagnostics reply, after the 0xFF reply code
+const MAX_DIAGNOSTICS_REPLY: usize = 35;
+
+//Level 3 optional feature bits - reported in the peripheral ID,
+//and switched on with EXPANSION_ENABLE_OPTIONS
+const OPTION_FTL: u8 = 0x01;
+const OPTION_32_BIT_MONETARY_FORMAT: u8 = 0x02;
+const OPTION_MULTICURRENCY: u8 = 0x04;
+const OPTION_NEGATIVE_VEND: u8 = 0x08;
+const OPTION_DATA_ENTRY: u8 = 0x10;
+const OPTION_ALWAYS_IDLE: u8 = 0x20;
+
+/// How we identify ourself to the cashless device, and what we tell it about
+/// the machine.  Processors use the identity fields to tell machines apart,
+/// so each machine should be given its' own serial number.
+#[derive(Copy, Clone, Format)]
+pub struct VmcConfig {
+    pub manufacturer_code: [u8; 3],
+    pub serial_number: [u8; 12],
+    pub model_number: [u8; 12],
+    pub software_version: [u8; 2],
+    pub feature_level: u8, //1-3
+    //Display we will share with the cashless device (0 columns, 0 rows = none)
+    pub display_columns: u8,
+    pub display_rows: u8,
+    pub display_info: u8,
+    //Price range of the machine's selections - None means "don't know"
+    pub max_price: Option<Money>,
+    pub min_price: Option<Money>,
+}
+
+impl VmcConfig {
+    pub fn new() -> Self {
+        Self {
+            manufacturer_code: *b"DMP",
+            serial_number: *b"000000000001",
+            model_number: *b"000000000001",
+            software_version: *b"01",
+            feature_level: 3,
+            display_columns: 0,
+            display_rows: 0,
+            display_info: 0,
+            max_price: None,
+            min_price: None,
+        }
+    }
+
+    pub fn manufacturer_code(mut self, manufacturer_code: [u8; 3]) -> Self {
+        self.manufacturer_code = manufacturer_code;
+        self
+    }
+
+    pub fn serial_number(mut self, serial_number: [u8; 12]) -> Self {
+        self.serial_number = serial_number;
+        self
+    }
+
+    pub fn model_number(mut self, model_number: [u8; 12]) -> Self {
+        self.model_number = model_number;
+        self
+    }
+
+    pub fn software_version(mut self, software_version: [u8; 2]) -> Self {
+        self.software_version = software_version;
+        self
+    }
+
+    /// VMC feature level - clamped to 1-3
+    pub fn feature_level(mut self, feature_level: u8) -> Self {
+        self.feature_level = feature_level.clamp(1, 3);
+        self
+    }
+
+    pub fn display(mut self, columns: u8, rows: u8, info: u8) -> Self {
+        self.display_columns = columns;
+        self.display_rows = rows;
+        self.display_info = info;
+        self
+    }
+
+    pub fn price_range(mut self, min_price: Money, max_price: Money) -> Self {
+        self.min_price = Some(min_price);
+        self.max_price = Some(max_price);
+        self
+    }
+
+    fn setup_data(&self) -> [u8; 6] {
+        [
+            SETUP_PREFIX,
+            SETUP_CONFIG_DATA,
+            self.feature_level,
+            self.display_columns,
+            self.display_rows,
+            self.display_info,
+        ]
+    }
+
+    //Scaled for the reader.  0xFFFF max, 0x0000 min means "don't know" - also sent
+    //for a price the reader can't take
+    fn max_min_price_data(&self, format: WireFormat) -> [u8; 6] {
+        let scaled = |price: Option<Money>| {
+            price
+                .and_then(|p| p.to_scaled(format))
+                .and_then(|s| u16::try_from(s).ok())
+        };
+        let max = scaled(self.max_price).unwrap_or(0xFFFF).to_be_bytes();
+        let min = scaled(self.min_price).unwrap_or(0x0000).to_be_bytes();
+        [SETUP_PREFIX, SETUP_MAX_MIN_PRICES, max[0], max[1], min[0], min[1]]
+    }
+
+    fn expansion_request_id_data(&self) -> [u8; 31] {
+        let mut data: [u8; 31] = [0x00; 31];
+        data[0] = EXPANSION_PREFIX;
+        data[1] = EXPANSION_REQUEST_ID;
+        data[2..5].copy_from_slice(&self.manufacturer_code);
+        data[5..17].copy_from_slice(&self.serial_number);
+        data[17..29].copy_from_slice(&self.model_number);
+        data[29..31].copy_from_slice(&self.software_version);
+        data
+    }
+}
+
+impl Default for VmcConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[derive(Format)]
 pub enum CashlessDeviceFeatureLevel {
@@ -117,6 +225,139 @@ pub struct CashlessDevice {
     pub supports_negative_vend: bool,
     pub supports_data_entry: bool,
     pub supports_always_idle: bool,
+
+    //How we identified ourself to the reader
+    pub config: VmcConfig,
+
+    //Whether the application wants the reader enabled
+    pub enabled: bool,
+
+    //Whether we managed to switch always idle mode on
+    pub always_idle_enabled: bool,
+}
+
+//A poll reply from the reader might be one of the following:
+#[derive(Copy, Clone, Format)]
+pub enum CashlessPollEvent {
+    JustReset,
+    //The reader reset unexpectedly, and we have re-initialised it (true if successful).
+    //Poll returns this in place of JustReset.
+    Reinitialised(bool),
+    //Funds available, if the reader knows
+    BeginSession(Option<Money>),
+    SessionCancelRequest,
+    //Amount approved
+    VendApproved(Money),
+    VendDenied,
+    EndSession,
+    Cancelled,
+    Malfunction(CashlessMalfunction),
+    //The reader's idea of what state it is in (not sent by L1 readers)
+    OutOfSequence(Option<CashlessReaderState>),
+    Ftl(FtlReply),
+    //Reply to a diagnostics command the reader didn't answer straight away
+    Diagnostics(DiagnosticsReply),
+    //Any reply we don't (yet) do anything with
+    Unhandled(u8),
+}
+
+/// A manufacturer specific diagnostics reply, minus the 0xFF reply code
+#[derive(Copy, Clone, Format)]
+pub struct DiagnosticsReply {
+    pub len: usize,
+    pub data: [u8; MAX_DIAGNOSTICS_REPLY],
+}
+
+#[derive(Copy, Clone, Format)]
+pub enum DiagnosticsStatus {
+    Reply(DiagnosticsReply),
+    //The reader ACKed - its' reply will come back from poll as CashlessPollEvent::Diagnostics
+    Pending,
+    Failed,
+}
+
+#[derive(Copy, Clone, Format)]
+pub enum VendOutcome {
+    Approved(Money), //Amount approved
+    Denied,
+    SessionEnded, //Reader cancelled, got out of sequence, or the session had already been closed
+    Malfunction(CashlessMalfunction),
+    TimedOut,
+}
+
+//Malfunction error codes - the upper nibble is the error, the lower nibble
+//is manufacturer specific and is attached to each variant.  The spec has three
+//manufacturer defined codes and two communications error codes, so those carry the
+//whole error code instead, to tell them apart.
+#[derive(Copy, Clone, Format)]
+pub enum CashlessMalfunction {
+    PaymentMediaError(u8),
+    InvalidPaymentMedia(u8),
+    TamperError(u8),
+    ManufacturerDefined(u8), //0x3_, 0x7_ or 0xB_ - whole error code attached
+    CommunicationsError(u8), //0x4_ or 0x9_ - whole error code attached
+    ReaderRequiresService(u8),
+    ReaderFailure(u8),
+    PaymentMediaJammed(u8),
+    RefundError(u8), //Internal reader credit lost
+    Unassigned(u8),  //Whole error code attached
+}
+
+impl CashlessMalfunction {
+    pub fn from_code(code: u8) -> Self {
+        let subcode = code & 0x0F;
+        match code >> 4 {
+            0x0 => CashlessMalfunction::PaymentMediaError(subcode),
+            0x1 => CashlessMalfunction::InvalidPaymentMedia(subcode),
+            0x2 => CashlessMalfunction::TamperError(subcode),
+            0x3 | 0x7 | 0xB => CashlessMalfunction::ManufacturerDefined(code),
+            0x4 | 0x9 => CashlessMalfunction::CommunicationsError(code),
+            0x5 => CashlessMalfunction::ReaderRequiresService(subcode),
+            0x8 => CashlessMalfunction::ReaderFailure(subcode),
+            0xA => CashlessMalfunction::PaymentMediaJammed(subcode),
+            0xC => CashlessMalfunction::RefundError(subcode),
+            _ => CashlessMalfunction::Unassigned(code),
+        }
+    }
+}
+
+//The state the reader reports itself to be in, when it tells us we are out of sequence
+#[derive(Copy, Clone, Format, N)]
+pub enum CashlessReaderState {
+    Inactive = 0x01,
+    Disabled = 0x02,
+    Enabled = 0x03,
+    SessionIdle = 0x04,
+    Vend = 0x05,
+    Revalue = 0x06,
+    NegativeVend = 0x07,
+}
+
+/// Tracks a (possibly multivend) session with the card reader
+#[derive(Copy, Clone, Format)]
+pub struct CashlessSession {
+    pub active: bool,
+    //None if the reader hasn't told us, or doesn't know (eg credit card)
+    pub funds_available: Option<Money>,
+    pub vends_approved: u8,
+    pub last_vend_amount: Option<Money>,
+}
+
+impl CashlessSession {
+    pub fn new() -> Self {
+        Self {
+            active: true,
+            funds_available: None,
+            vends_approved: 0,
+            last_vend_amount: None,
+        }
+    }
+}
+
+impl Default for CashlessSession {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CashlessDevice {
@@ -142,9 +383,9 @@ impl CashlessDevice {
             POLL_REPLY_CANCELLED => 1,
             POLL_REPLY_PERIPHERAL_ID => {
                 match self.feature_level {
-                    //Because the library identifies as L3 VMC, the L3 device will give us the option bits
+                    //If we identify as an L3 VMC, the L3 device will give us the option bits
                     //making its' reply 34 bytes long
-                    CashlessDeviceFeatureLevel::Level3 => 34,
+                    CashlessDeviceFeatureLevel::Level3 if self.config.feature_level >= 3 => 34,
                     _ => 30,
                 }
             }
@@ -158,6 +399,9 @@ impl CashlessDevice {
             POLL_REPLY_REVALUE_LIMIT_AMOUNT => 3,
             POLL_REPLY_TIME_DATE_REQUEST => 1,
             POLL_REPLY_DATA_ENTRY_REQUEST => 2,
+            //Variable length - this is the longest it can be
+            POLL_REPLY_DIAGNOSTICS => 36,
+            x if ftl::is_ftl_reply(x) => ftl::reply_length(x),
             _ => {
                 defmt::debug!("Got asked for length of unknown poll cmd {=u8}", poll_cmd);
                 1
@@ -165,13 +409,18 @@ impl CashlessDevice {
         }
     }
 
-    pub fn init<T: embedded_io::Write + embedded_io::Read>(bus: &mut Mdb<T>) -> Option<Self> {
+    /// currency is the one the machine works in - a reader set up for another is refused
+    pub fn init<T: embedded_io::Write + embedded_io::Read>(
+        bus: &mut Mdb<T>,
+        config: &VmcConfig,
+        currency: Currency,
+    ) -> Option<Self> {
         let mut buf: [u8; 64] = [0x00; 64];
 
         bus.send_data_and_confirm_ack(&[RESET]);
         bus.send_data(&[POLL_CMD]);
         match bus.receive_response(&mut buf) {
-            MDBResponse::Data(x) => {
+            MDBResponse::Data(_) => {
                 if buf[0] != POLL_REPLY_JUST_RESET {
                     defmt::debug!("Unexpected reply from cashless device post reset");
                     return None;
@@ -179,10 +428,25 @@ impl CashlessDevice {
                     defmt::debug!("Received JUST_RESET from cashless device post poll");
                 }
             }
-            MDBResponse::StatusMsg(x) => {}
+            MDBResponse::StatusMsg(_) => {}
         };
 
-        bus.send_data(&VMC_SETUP_DATA);
+        let mut c = Self::setup(bus, config)?;
+        currency.check(c.currency()).ok()?;
+        c.set_device_enabled(bus, true);
+
+        Some(c)
+    }
+
+    /// Setup, identification and option enable - everything init does after the reset,
+    /// short of enabling the reader.
+    fn setup<T: embedded_io::Write + embedded_io::Read>(
+        bus: &mut Mdb<T>,
+        config: &VmcConfig,
+    ) -> Option<Self> {
+        let mut buf: [u8; 64] = [0x00; 64];
+
+        bus.send_data(&config.setup_data());
         match bus.receive_response(&mut buf) {
             MDBResponse::Data(len) => {
                 if len != 8 {
@@ -190,7 +454,7 @@ impl CashlessDevice {
                     return None;
                 }
             }
-            MDBResponse::StatusMsg(x) => {
+            MDBResponse::StatusMsg(_) => {
                 defmt::error!("Cashless device failed to reply with setup data");
                 return None;
             }
@@ -206,6 +470,9 @@ impl CashlessDevice {
         let country_code: u16 = (buf[0x02] as u16) << 8 | buf[0x03] as u16;
         let scale_factor = buf[0x04];
         let decimal_places = buf[0x05];
+        let currency = Currency::from_mdb_code(country_code);
+        defmt::debug!("Card reader currency {}", currency);
+        currency::check_decimal_places(currency, decimal_places);
         let max_response_time = buf[0x06];
         //Optional feature flags
         let can_restore_funds = buf[0x07] & 0x01 != 0;
@@ -214,13 +481,19 @@ impl CashlessDevice {
         let supports_cash_sale_cmd = buf[0x07] & 0x08 != 0;
 
         //Min max price data next
-        bus.send_data_and_confirm_ack(&VMC_MAX_MIN_PRICE_DATA);
+        bus.send_data_and_confirm_ack(
+            &config.max_min_price_data(WireFormat::new(currency, scale_factor, decimal_places)),
+        );
+
+        //L3 readers only send their optional feature bits to an L3 VMC
+        let has_options = matches!(feature_level, CashlessDeviceFeatureLevel::Level3)
+            && config.feature_level >= 3;
 
-        bus.send_data(&VMC_EXPANSION_REQUEST_ID_DATA); //as above
+        bus.send_data(&config.expansion_request_id_data());
         match bus.receive_response(&mut buf) {
             MDBResponse::Data(len) => {
-                //34 bytes if level 3 (the VMC reports L3)
-                if matches!(feature_level, CashlessDeviceFeatureLevel::Level3) {
+                //34 bytes if level 3 (and the VMC reports L3)
+                if has_options {
                     if len != 34 {
                         defmt::error!(
                             "L3 cashless device replied with wrong length expansion data ( {} )",
@@ -243,8 +516,15 @@ impl CashlessDevice {
             }
         }
 
+        //Only L3 devices send the option bits
+        let options = if has_options {
+            buf[33]
+        } else {
+            0x00
+        };
+
         //Buffer will now contain correct length of data for parsing expansion request
-        let c = CashlessDevice {
+        let mut c = CashlessDevice {
             feature_level,
             country_code,
             scale_factor,
@@ -264,35 +544,80 @@ impl CashlessDevice {
             software_version: buf[28..30].try_into().unwrap(),
 
             //Level 3 features
-            supports_ftl: buf[33] & 0x01 != 0,
-            monetary_format_32_bit: buf[33] & 0x02 != 0,
-            supports_multicurrency: buf[33] & 0x04 != 0,
-            supports_negative_vend: buf[33] & 0x08 != 0,
-            supports_data_entry: buf[33] & 0x10 != 0,
-            supports_always_idle: buf[33] & 0x20 != 0,
+            supports_ftl: options & OPTION_FTL != 0,
+            monetary_format_32_bit: options & OPTION_32_BIT_MONETARY_FORMAT != 0,
+            supports_multicurrency: options & OPTION_MULTICURRENCY != 0,
+            supports_negative_vend: options & OPTION_NEGATIVE_VEND != 0,
+            supports_data_entry: options & OPTION_DATA_ENTRY != 0,
+            supports_always_idle: options & OPTION_ALWAYS_IDLE != 0,
+
+            config: *config,
+            enabled: false,
+            always_idle_enabled: false,
         };
-        //Enable always idle
-        bus.send_data_and_confirm_ack(&[0x17, 0x04, 0x00, 0x00, 0x00, 0x20]);
 
-        c.set_device_enabled(bus, true);
+        //Enable the optional features we use, but only if the reader says it can do them
+        let mut options_to_enable: u8 = 0x00;
+        if c.supports_always_idle {
+            options_to_enable |= OPTION_ALWAYS_IDLE;
+        }
+        if c.supports_ftl {
+            options_to_enable |= OPTION_FTL;
+        }
+        if options_to_enable != 0x00 {
+            if bus.send_data_and_confirm_ack(&[
+                EXPANSION_PREFIX,
+                EXPANSION_ENABLE_OPTIONS,
+                0x00,
+                0x00,
+                0x00,
+                options_to_enable,
+            ]) {
+                c.always_idle_enabled = c.supports_always_idle;
+            } else {
+                defmt::debug!("Card reader failed to enable optional features");
+            }
+        }
 
         Some(c)
     }
 
+    /// The reader has reset behind our back (eg a brownout), so has lost its' setup.
+    /// Set it up again, and put it back in the enabled state the application wanted.
+    fn reinitialise<T: embedded_io::Write + embedded_io::Read>(&mut self, bus: &mut Mdb<T>) -> bool {
+        defmt::info!("Card reader reset unexpectedly - re-initialising");
+        match Self::setup(bus, &self.config) {
+            Some(c) => {
+                //Keep to the currency we were set up with
+                if self.currency().check(c.currency()).is_err() {
+                    return false;
+                }
+                let enabled = self.enabled;
+                *self = c;
+                self.set_device_enabled(bus, enabled)
+            }
+            None => {
+                defmt::error!("Card reader failed to re-initialise");
+                false
+            }
+        }
+    }
+
     pub fn record_cash_transaction<T: embedded_io::Write + embedded_io::Read>(
         &self,
         bus: &mut Mdb<T>,
-        unscaled_amount: u16,
+        amount: Money,
         address: [u8; 2],
     ) -> bool {
-        let mut buf: [u8; 64] = [0x00; 64];
-
-        let amount = unscaled_amount.to_le_bytes();
+        let amount = match self.to_wire(amount) {
+            Some(scaled) => scaled.to_be_bytes(),
+            None => return false,
+        };
         if bus.send_data_and_confirm_ack(&[
             VEND_PREFIX,
             VEND_CASH_SALE,
-            amount[1],
             amount[0],
+            amount[1],
             address[0],
             address[1],
         ]) {
@@ -305,60 +630,382 @@ impl CashlessDevice {
         }
     }
 
+    /// Poll the reader, and split its' reply into individual events.
+    /// The reader may chain several replies into a single message, so
+    /// poll_response_length is used to tokenize them.
+    pub fn poll<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> [Option<CashlessPollEvent>; 8] {
+        let mut events: [Option<CashlessPollEvent>; 8] = [None; 8];
+
+        let mut buf: [u8; 64] = [0x00; 64];
+        bus.send_data(&[POLL_CMD]);
+        if let MDBResponse::Data(len) = bus.receive_response(&mut buf) {
+            events = self.parse_poll_events(&buf[0..len]);
+        }
+
+        //Deal with the errors here, so the application doesn't have to - they are still
+        //returned, so it knows they happened
+        for event in events.iter_mut() {
+            match event {
+                Some(CashlessPollEvent::JustReset) => {
+                    *event = Some(CashlessPollEvent::Reinitialised(self.reinitialise(bus)));
+                }
+                Some(CashlessPollEvent::Malfunction(malfunction)) => {
+                    defmt::error!("Card reader malfunction: {}", malfunction);
+                }
+                Some(CashlessPollEvent::OutOfSequence(state)) => {
+                    let state = *state;
+                    self.recover_from_out_of_sequence(bus, state);
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+
+    //Split a poll reply into the individual replies chained in it
+    fn parse_poll_events(&self, buf: &[u8]) -> [Option<CashlessPollEvent>; 8] {
+        let mut events: [Option<CashlessPollEvent>; 8] = [None; 8];
+        let mut event_count: usize = 0;
+        let len = buf.len();
+        let mut offset: usize = 0;
+        while offset < len && event_count < events.len() {
+            let mut reply_len = self.poll_response_length(buf[offset]);
+            if buf[offset] == ftl::FTL_REPLY_SEND_BLOCK || buf[offset] == POLL_REPLY_DIAGNOSTICS {
+                //Variable length, so it takes up the rest of the message
+                reply_len = reply_len.min(len - offset);
+            }
+            if offset + reply_len > len {
+                defmt::debug!(
+                    "Truncated poll reply from card reader: {=[u8]:#04x}",
+                    buf[offset..len]
+                );
+                break;
+            }
+            events[event_count] = Some(self.parse_poll_reply(&buf[offset..offset + reply_len]));
+            event_count += 1;
+            offset += reply_len;
+        }
+        events
+    }
+
+    /// The reader thinks we have sent a command out of sequence.  Use the state it
+    /// says it is in to get back in step with it.
+    fn recover_from_out_of_sequence<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        state: Option<CashlessReaderState>,
+    ) -> bool {
+        defmt::error!("Card reader reports command out of sequence - state {}", state);
+        match state {
+            Some(CashlessReaderState::Inactive) => {
+                //Lost its' setup, so needs re-initialising
+                self.reinitialise(bus)
+            }
+            Some(CashlessReaderState::Disabled) | Some(CashlessReaderState::Enabled) => {
+                //Not in a session - just make sure it is enabled/disabled as we want it
+                self.send_enable(bus, self.enabled)
+            }
+            _ => {
+                //Stuck in a session we don't know about (or an L1 reader that won't say),
+                //so end it, and put the reader back how we want it
+                self.end_session(bus);
+                self.send_enable(bus, self.enabled)
+            }
+        }
+    }
+
+    fn parse_poll_reply(&self, reply: &[u8]) -> CashlessPollEvent {
+        match reply[0] {
+            POLL_REPLY_JUST_RESET => CashlessPollEvent::JustReset,
+            POLL_REPLY_BEGIN_SESSION => {
+                let funds: u16 = (reply[1] as u16) << 8 | reply[2] as u16;
+                //0xFFFF means the reader doesn't know how much is available (eg a credit card)
+                CashlessPollEvent::BeginSession(if funds == 0xFFFF {
+                    None
+                } else {
+                    Some(self.from_wire(funds))
+                })
+            }
+            POLL_REPLY_SESSION_CANCEL_REQUEST => CashlessPollEvent::SessionCancelRequest,
+            POLL_REPLY_VEND_APPROVED => {
+                CashlessPollEvent::VendApproved(
+                    self.from_wire((reply[1] as u16) << 8 | reply[2] as u16),
+                )
+            }
+            POLL_REPLY_VEND_DENIED => CashlessPollEvent::VendDenied,
+            POLL_REPLY_END_SESSION => CashlessPollEvent::EndSession,
+            POLL_REPLY_CANCELLED => CashlessPollEvent::Cancelled,
+            POLL_REPLY_MALFUNCTION => {
+                CashlessPollEvent::Malfunction(CashlessMalfunction::from_code(reply[1]))
+            }
+            POLL_REPLY_OUT_OF_SEQUENCE => CashlessPollEvent::OutOfSequence(if reply.len() > 1 {
+                CashlessReaderState::n(reply[1])
+            } else {
+                None
+            }),
+            POLL_REPLY_DIAGNOSTICS => {
+                let mut diagnostics = DiagnosticsReply {
+                    len: reply.len() - 1,
+                    data: [0x00; MAX_DIAGNOSTICS_REPLY],
+                };
+                diagnostics.data[0..diagnostics.len].copy_from_slice(&reply[1..]);
+                CashlessPollEvent::Diagnostics(diagnostics)
+            }
+            x if ftl::is_ftl_reply(x) => match ftl::parse_reply(reply) {
+                Some(ftl_reply) => CashlessPollEvent::Ftl(ftl_reply),
+                None => CashlessPollEvent::Unhandled(x),
+            },
+            other => {
+                defmt::debug!("Unhandled poll reply from card reader: {=[u8]:#04x}", reply);
+                CashlessPollEvent::Unhandled(other)
+            }
+        }
+    }
+
     pub fn start_transaction<T: embedded_io::Write + embedded_io::Read>(
-        &self,
+        &mut self,
         bus: &mut Mdb<T>,
-        unscaled_amount: u16,
+        price: Money,
         address: [u8; 2],
     ) -> bool {
-        let mut buf: [u8; 64] = [0x00; 64];
+        let (session, outcome) = self.request_session(bus, price, address);
+        let success = matches!(outcome, VendOutcome::Approved(_));
+        if !success && session.active {
+            //need to end session if denied.
+            self.end_session(bus);
+        }
+        success
+    }
 
-        let amount = unscaled_amount.to_le_bytes();
+    /// Poll the reader while idle.  If the customer has tapped their card before
+    /// making a selection, the reader begins a session, which is returned along
+    /// with the funds available (if known).  Call session_vend_request once the
+    /// selection is made.
+    pub fn poll_for_session<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> Option<CashlessSession> {
+        for event in self.poll(bus).into_iter().flatten() {
+            if let CashlessPollEvent::BeginSession(funds) = event {
+                defmt::debug!("Card reader began session - funds {}", funds);
+                let mut session = CashlessSession::new();
+                session.funds_available = funds;
+                return Some(session);
+            }
+        }
+        None
+    }
+
+    /// The customer has made a selection before presenting their card.
+    /// In always idle mode, the vend request can be sent straight away and the reader
+    /// will wait for the card itself.  Otherwise we wait for the reader to begin a session
+    /// (ie the card to be presented) before sending the vend request.
+    /// The session is returned so that further vends can be requested on a multivend reader.
+    pub fn request_session<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        price: Money,
+        address: [u8; 2],
+    ) -> (CashlessSession, VendOutcome) {
+        if !self.always_idle_enabled {
+            //Wait a max of 150 cycles (30 seconds) for someone to present a card
+            let mut session = None;
+            for _ in 0..150 {
+                session = self.poll_for_session(bus);
+                if session.is_some() {
+                    break;
+                }
+                bus.timer.delay_ms(200);
+            }
+            return match session {
+                Some(mut session) => {
+                    let outcome = self.session_vend_request(bus, &mut session, price, address);
+                    (session, outcome)
+                }
+                None => {
+                    defmt::debug!("No card presented");
+                    let mut session = CashlessSession::new();
+                    session.active = false;
+                    (session, VendOutcome::TimedOut)
+                }
+            };
+        }
+
+        let mut session = CashlessSession::new();
+        let outcome = self.session_vend_request(bus, &mut session, price, address);
+        (session, outcome)
+    }
+
+    /// Request a vend within a session.  On a multivend capable reader this can be
+    /// called repeatedly with the same session until the customer is finished, at which
+    /// point finish_session should be called.
+    pub fn session_vend_request<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        session: &mut CashlessSession,
+        price: Money,
+        address: [u8; 2],
+    ) -> VendOutcome {
+        if let Err(outcome) = self.send_vend_request(bus, session, price, address) {
+            return outcome;
+        }
+
+        //Send poll command, and wait a max of 150 cycles (30 seconds) for someone to present a card
+        for _ in 0..150 {
+            if let Some(outcome) = self.vend_request_poll(bus, session) {
+                return outcome;
+            }
+            bus.timer.delay_ms(200);
+        }
+        VendOutcome::TimedOut
+    }
+
+    /// Send a vend request without waiting for the answer - follow it with
+    /// vend_request_poll.  Err if the vend can't be requested at all.
+    pub fn send_vend_request<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        session: &CashlessSession,
+        price: Money,
+        address: [u8; 2],
+    ) -> Result<(), VendOutcome> {
+        if !session.active {
+            defmt::debug!("Vend requested on a session that has already ended");
+            return Err(VendOutcome::SessionEnded);
+        }
+        if session.vends_approved > 0 && !self.multivend_capable {
+            defmt::debug!("Card reader is not multivend capable - one vend per session only");
+            return Err(VendOutcome::Denied);
+        }
+        if let Some(funds) = session.funds_available {
+            if !funds.covers(price) {
+                defmt::debug!("Insufficient funds for vend - {} available", funds);
+                return Err(VendOutcome::Denied);
+            }
+        }
+
+        let amount = match self.to_wire(price) {
+            Some(scaled) => scaled.to_be_bytes(),
+            None => return Err(VendOutcome::Denied),
+        };
         bus.send_data_and_confirm_ack(&[
             VEND_PREFIX,
             VEND_REQUEST,
-            amount[1],
             amount[0],
+            amount[1],
             address[0],
             address[1],
         ]);
- 
-        //Send poll command, and wait a max of 150 cycles (30 seconds) for someone to present a card
-        let mut success = false;
-        for i in 0..150 {
-            bus.send_data(&[POLL_CMD]);
-            match bus.receive_response(&mut buf) {
-                MDBResponse::Data(len) => match buf[0] {
-                    POLL_REPLY_VEND_APPROVED => {
-                        let amount: u16 = (buf[1] as u16) << 8 | buf[2] as u16;
-                        defmt::debug!("Card reader approved vend - up to  {}", amount);
-                        success = true;
-                        break;
-                    }
-                    POLL_REPLY_VEND_DENIED => {
-                        defmt::debug!("Card reader denied vend");
-                        break;
-                    }
-                    POLL_REPLY_SESSION_CANCEL_REQUEST => {
-                        defmt::debug!("Card reader requested end of session");
-                        break;
-                    }
-                    _ => {
-                        defmt::debug!(
-                            "Unexpected reply from card reader to vend request: {=[u8]:#04x}",
-                            buf[0..len]
-                        );
+        Ok(())
+    }
+
+    /// Poll once for the answer to send_vend_request.  None while the reader is still
+    /// waiting (eg for the card to be presented).
+    pub fn vend_request_poll<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        session: &mut CashlessSession,
+    ) -> Option<VendOutcome> {
+        for event in self.poll(bus).into_iter().flatten() {
+            match event {
+                CashlessPollEvent::BeginSession(funds) => {
+                    defmt::debug!("Card reader began session");
+                    session.funds_available = funds;
+                }
+                CashlessPollEvent::VendApproved(amount) => {
+                    defmt::debug!("Card reader approved vend - up to  {}", amount);
+                    session.vends_approved += 1;
+                    session.last_vend_amount = Some(amount);
+                    if let Some(funds) = session.funds_available {
+                        session.funds_available = funds.saturating_sub(amount);
                     }
-                },
-                MDBResponse::StatusMsg(x) => {}
-            };
-            bus.timer.delay_ms(200);
+                    return Some(VendOutcome::Approved(amount));
+                }
+                CashlessPollEvent::VendDenied => {
+                    defmt::debug!("Card reader denied vend");
+                    return Some(VendOutcome::Denied);
+                }
+                CashlessPollEvent::SessionCancelRequest => {
+                    defmt::debug!("Card reader requested end of session");
+                    self.finish_session(bus, session);
+                    return Some(VendOutcome::SessionEnded);
+                }
+                CashlessPollEvent::EndSession => {
+                    session.active = false;
+                    return Some(VendOutcome::SessionEnded);
+                }
+                CashlessPollEvent::OutOfSequence(_) | CashlessPollEvent::Reinitialised(_) => {
+                    //Poll will have ended the session while recovering
+                    session.active = false;
+                    return Some(VendOutcome::SessionEnded);
+                }
+                CashlessPollEvent::Malfunction(malfunction) => {
+                    return Some(VendOutcome::Malfunction(malfunction));
+                }
+                _ => {
+                    defmt::debug!("Unexpected reply from card reader to vend request");
+                }
+            }
         }
-        if ! success {
-            //need to end session if denied.
-            self.end_session(bus);
+        None
+    }
+
+    /// Poll the reader while a session is idle between vends.  If the reader asks
+    /// for the session to be cancelled, the session is ended.  Returns whether the
+    /// session is still active.
+    pub fn session_poll<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        session: &mut CashlessSession,
+    ) -> bool {
+        for event in self.poll(bus).into_iter().flatten() {
+            match event {
+                CashlessPollEvent::BeginSession(funds) => {
+                    session.funds_available = funds;
+                }
+                CashlessPollEvent::SessionCancelRequest => {
+                    defmt::debug!("Card reader requested end of session");
+                    self.finish_session(bus, session);
+                }
+                CashlessPollEvent::EndSession
+                | CashlessPollEvent::OutOfSequence(_)
+                | CashlessPollEvent::Reinitialised(_) => {
+                    session.active = false;
+                }
+                _ => {}
+            }
         }
-        success
+        session.active
+    }
+
+    /// Report a failed vend within a session.  If the reader can restore funds,
+    /// the amount of the last approved vend is returned to the session balance.
+    pub fn session_vend_failed<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        session: &mut CashlessSession,
+    ) -> bool {
+        let refunded = self.vend_failed(bus);
+        if refunded && self.can_restore_funds {
+            if let (Some(funds), Some(amount)) = (session.funds_available, session.last_vend_amount) {
+                session.funds_available = funds.saturating_add(amount);
+            }
+        }
+        session.last_vend_amount = None;
+        refunded
+    }
+
+    /// The customer has finished - close the session with the reader
+    pub fn finish_session<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        session: &mut CashlessSession,
+    ) -> bool {
+        session.active = false;
+        self.end_session(bus)
     }
 
     pub fn cancel_transaction<T: embedded_io::Write + embedded_io::Read>(
@@ -370,16 +1017,11 @@ impl CashlessDevice {
 
         let mut buf: [u8; 64] = [0x00; 64];
         bus.send_data(&[POLL_CMD]);
-        match bus.receive_response(&mut buf) {
-            MDBResponse::Data(len) => match buf[0] {
-                POLL_REPLY_VEND_DENIED => {
-                    let amount: u16 = (buf[1] as u16) << 8 | buf[2] as u16;
-                    defmt::debug!("Transaction cancelled");
-                    return true;
-                }
-                _=>{}
+        if let MDBResponse::Data(_) = bus.receive_response(&mut buf) {
+            if buf[0] == POLL_REPLY_VEND_DENIED {
+                defmt::debug!("Transaction cancelled");
+                return true;
             }
-            _=> {},
         }
         false
     }
@@ -400,11 +1042,10 @@ impl CashlessDevice {
         bus.send_data_and_confirm_ack(&[VEND_PREFIX, VEND_FAILURE]);
         //poll should get 0x06 -vend denied.
         //then we move to end session.
-        let mut buf: [u8; 64] = [0x00; 64];
         bus.send_data(&[POLL_CMD]);
 
         let mut refund_complete = false;
-        for i in 0..100 {
+        for _ in 0..100 {
             if bus.send_data_and_confirm_ack(&[POLL_CMD]) {
                 refund_complete = true;
                 break;
@@ -442,13 +1083,93 @@ impl CashlessDevice {
                     );
                 }
             },
-            MDBResponse::StatusMsg(x) => {}
+            MDBResponse::StatusMsg(_) => {}
         };
         defmt::error!("end session failed!");
-        return false;
+        false
+    }
+
+    /// Send a manufacturer specific diagnostics command to the reader (eg to query
+    /// signal strength or modem state on a telemetry reader).  The reader may reply
+    /// straight away, or just ACK and send its' reply with a later poll.
+    pub fn diagnostics<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        payload: &[u8],
+    ) -> DiagnosticsStatus {
+        if payload.len() > MAX_DIAGNOSTICS_PAYLOAD {
+            defmt::error!("Diagnostics payload too long ({} bytes)", payload.len());
+            return DiagnosticsStatus::Failed;
+        }
+        let mut msg: [u8; 2 + MAX_DIAGNOSTICS_PAYLOAD] = [0x00; 2 + MAX_DIAGNOSTICS_PAYLOAD];
+        msg[0] = EXPANSION_PREFIX;
+        msg[1] = EXPANSION_DIAGNOSTICS;
+        msg[2..2 + payload.len()].copy_from_slice(payload);
+
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&msg[0..2 + payload.len()]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(len) => match self.parse_poll_reply(&buf[0..len]) {
+                CashlessPollEvent::Diagnostics(reply) => DiagnosticsStatus::Reply(reply),
+                _ => {
+                    defmt::error!(
+                        "Unexpected reply from card reader to diagnostics: {=[u8]:#04x}",
+                        buf[0..len]
+                    );
+                    DiagnosticsStatus::Failed
+                }
+            },
+            MDBResponse::StatusMsg(MDBStatus::ACK) => DiagnosticsStatus::Pending,
+            MDBResponse::StatusMsg(_) => {
+                defmt::error!("Card reader did not reply to diagnostics");
+                DiagnosticsStatus::Failed
+            }
+        }
+    }
+
+    pub fn currency(&self) -> Currency {
+        Currency::from_mdb_code(self.country_code)
+    }
+
+    pub fn wire_format(&self) -> WireFormat {
+        WireFormat::new(self.currency(), self.scale_factor, self.decimal_places)
+    }
+
+    /// Convert an amount to the reader's scaled format for the vend commands.
+    /// None (and logged) if it is in the wrong currency, or can't be sent exactly.
+    pub fn to_wire(&self, amount: Money) -> Option<u16> {
+        if amount.currency != self.currency() {
+            defmt::error!("{} is not in the card reader's currency", amount);
+            return None;
+        }
+        match amount.to_scaled(self.wire_format()).map(u16::try_from) {
+            Some(Ok(scaled)) => Some(scaled),
+            _ => {
+                defmt::error!(
+                    "{} cannot be sent to the card reader (scale factor {})",
+                    amount,
+                    self.scale_factor
+                );
+                None
+            }
+        }
+    }
+
+    /// Convert a scaled amount from the reader
+    pub fn from_wire(&self, scaled: u16) -> Money {
+        Money::from_scaled(scaled as u32, self.wire_format())
     }
 
     pub fn set_device_enabled<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        enable: bool,
+    ) -> bool {
+        self.enabled = enable;
+        self.send_enable(bus, enable)
+    }
+
+    fn send_enable<T: embedded_io::Write + embedded_io::Read>(
         &self,
         bus: &mut Mdb<T>,
         enable: bool,
@@ -460,3 +1181,87 @@ impl CashlessDevice {
         }
     }
 }
+
+impl<T: embedded_io::Write + embedded_io::Read> FtlDevice<Mdb<T>> for CashlessDevice {
+    fn ftl_address(&self) -> u8 {
+        CASHLESS_ADDRESS
+    }
+
+    fn ftl_command_prefix(&self) -> u8 {
+        EXPANSION_PREFIX
+    }
+
+    fn ftl_poll(&mut self, bus: &mut Mdb<T>) -> Option<FtlReply> {
+        for event in self.poll(bus).into_iter().flatten() {
+            match event {
+                CashlessPollEvent::Ftl(reply) => return Some(reply),
+                _ => defmt::debug!("Ignoring card reader poll event during FTL transfer"),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    fn reader() -> CashlessDevice {
+        CashlessDevice {
+            feature_level: CashlessDeviceFeatureLevel::Level2,
+            country_code: 0x1978,
+            scale_factor: 5,
+            decimal_places: 2,
+            max_response_time: 5,
+            can_restore_funds: false,
+            multivend_capable: false,
+            has_display: false,
+            supports_cash_sale_cmd: false,
+            manufacturer_code: [0x00; 3],
+            serial_number: [0x00; 12],
+            model_number: [0x00; 12],
+            software_version: [0x00; 2],
+            supports_ftl: false,
+            monetary_format_32_bit: false,
+            supports_multicurrency: false,
+            supports_negative_vend: false,
+            supports_data_entry: false,
+            supports_always_idle: false,
+            config: VmcConfig::new(),
+            enabled: true,
+            always_idle_enabled: false,
+        }
+    }
+
+    #[test]
+    fn diagnostics_reply_in_poll() {
+        let events = reader().parse_poll_events(&[POLL_REPLY_VEND_DENIED, 0xFF, 0x01, 0x02, 0x03]);
+        assert!(matches!(events[0], Some(CashlessPollEvent::VendDenied)));
+        match events[1] {
+            Some(CashlessPollEvent::Diagnostics(reply)) => {
+                assert_eq!(reply.data[0..reply.len], [0x01, 0x02, 0x03])
+            }
+            _ => panic!("Diagnostics reply not parsed"),
+        }
+        assert!(events[2].is_none());
+    }
+
+    #[test]
+    fn chained_poll_replies() {
+        //Vend approved for 20 (ie 1.00), then end session
+        let events = reader().parse_poll_events(&[
+            POLL_REPLY_VEND_APPROVED,
+            0x00,
+            0x14,
+            POLL_REPLY_END_SESSION,
+        ]);
+        match events[0] {
+            Some(CashlessPollEvent::VendApproved(amount)) => {
+                assert_eq!(amount, Money::new(100, Currency::Iso(978)))
+            }
+            _ => panic!("Vend approved not parsed"),
+        }
+        assert!(matches!(events[1], Some(CashlessPollEvent::EndSession)));
+    }
+}
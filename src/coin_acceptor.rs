@@ -1,3 +1,8 @@
+use crate::ftl;
+use crate::currency;
+use crate::currency::Currency;
+use crate::ftl::{FtlDevice, FtlReply};
+use crate::money::{Money, WireFormat};
 use crate::MDBResponse;
 use crate::MDBStatus;
 use crate::Mdb;
@@ -6,6 +11,9 @@ use defmt::Format;
 use embedded_hal::delay::DelayNs;
 use enumn::N;
 
+//Our address on the bus
+const COIN_ACCEPTOR_ADDRESS: u8 = 0x08;
+
 //All coin acceptors should support these commands
 const RESET_CMD: u8 = 0x08;
 const SETUP_CMD: u8 = 0x09;
@@ -24,7 +32,13 @@ const L3_PAYOUT_CMD: u8 = 0x02;
 const L3_PAYOUT_STATUS_CMD: u8 = 0x03;
 const L3_PAYOUT_VALUE_POLL_CMD: u8 = 0x04;
 const L3_DIAG_CMD: u8 = 0x05;
+const L3_CONTROLLED_FILL_REPORT_CMD: u8 = 0x06;
+const L3_CONTROLLED_PAYOUT_REPORT_CMD: u8 = 0x07;
+
+//How often we ask the changer how an L3 payout is going
+const L3_PAYOUT_POLL_INTERVAL_MS: u32 = 50;
 
+#[allow(non_camel_case_types)]
 pub enum L3_OPTIONAL_FEATURE {
     AltPayout = 0x01,
     ExtDiag = 0x02,
@@ -40,6 +54,225 @@ pub struct CoinAcceptor {
     pub decimal_places: u8,
     pub coin_types: [Option<CoinType>; 16],
     pub l3_features: Option<CoinAcceptorL3Features>,
+    //L3 optional features currently switched on
+    pub l3_enabled_features: u8,
+    //Coin types the application has enabled, restored if the changer resets
+    pub enabled_coins: u16,
+    pub payout_strategy: PayoutStrategy,
+    //How long an L3 payout may take before we give up on it
+    pub payout_timeout_ms: u32,
+    l3_payout: Option<L3PayoutState>,
+    //If set, the coin enable mask is worked out from this after each poll and payout
+    pub acceptance_policy: Option<CoinAcceptancePolicy>,
+    //Set by the acceptance policy if we can't be sure of giving change
+    pub exact_change_only: bool,
+    //Tube counts and change_needed that exact_change_only was worked out for
+    exact_change_checked: Option<([u8; 16], Option<Money>)>,
+    //All coins are inhibited while set, whatever enabled_coins says (eg a fault)
+    pub out_of_service: bool,
+}
+
+/// Which coins to accept, by value rather than coin type
+#[derive(Copy, Clone, Format)]
+pub struct CoinAcceptancePolicy {
+    //Coin values we won't accept
+    pub inhibited_values: [Option<Money>; 16],
+    //If false, coins that can't go into a tube (full, or not routeable) are refused
+    pub allow_cashbox: bool,
+    //Exact change only is indicated if we can't pay every amount up to this much change (None = never)
+    pub change_needed: Option<Money>,
+}
+
+impl CoinAcceptancePolicy {
+    pub fn new() -> Self {
+        Self {
+            inhibited_values: [None; 16],
+            allow_cashbox: true,
+            change_needed: None,
+        }
+    }
+
+    pub fn inhibit_value(mut self, value: Money) -> Self {
+        if !self.inhibited_values.contains(&Some(value)) {
+            if let Some(slot) = self.inhibited_values.iter_mut().find(|v| v.is_none()) {
+                *slot = Some(value);
+            }
+        }
+        self
+    }
+
+    pub fn accept_value(mut self, value: Money) -> Self {
+        for v in self.inhibited_values.iter_mut() {
+            if *v == Some(value) {
+                *v = None;
+            }
+        }
+        self
+    }
+
+    pub fn allow_cashbox(mut self, allow_cashbox: bool) -> Self {
+        self.allow_cashbox = allow_cashbox;
+        self
+    }
+
+    pub fn change_needed(mut self, amount: Money) -> Self {
+        self.change_needed = Some(amount);
+        self
+    }
+}
+
+impl Default for CoinAcceptancePolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Copy, Clone, Format)]
+struct L3PayoutState {
+    started_at: u64, //Timer counter, uS
+    paid_so_far: Money,
+}
+
+/// How an L3 payout is getting on - each carries the amount paid so far
+#[derive(Copy, Clone, Format)]
+pub enum PayoutProgress {
+    Paying(Money),
+    Complete(Money),
+    //Gave up waiting for the changer to finish
+    Incomplete(Money),
+}
+
+/// How to choose between coin combinations when paying out
+#[derive(Copy, Clone, Format)]
+pub enum PayoutStrategy {
+    //Use as many high value coins as possible, keeping low value coins for making change later
+    PreserveLowValueCoins,
+    //Use the smallest number of coins
+    FewestCoins,
+}
+
+/// Number of coins of each type to pay out, and what they add up to
+#[derive(Copy, Clone, Format)]
+pub struct PayoutPlan {
+    pub coins: [u8; 16],
+    pub total: Money,
+}
+
+//Upper limit on the payout search, so an awkward coin set can't stall us
+const MAX_PAYOUT_SEARCH_STEPS: u32 = 20000;
+
+//Amounts of change the exact change check covers, in steps of the coin values' common divisor
+const MAX_CHANGE_STEPS: usize = 2048;
+
+//Bit n set if n steps of change can be paid
+struct ChangeBitset {
+    words: [u64; MAX_CHANGE_STEPS / 64],
+}
+
+impl ChangeBitset {
+    //Only nothing (no change) to start with
+    fn new() -> Self {
+        let mut words = [0; MAX_CHANGE_STEPS / 64];
+        words[0] = 0x01;
+        Self { words }
+    }
+
+    fn get(&self, n: usize) -> bool {
+        self.words[n / 64] & (0x01 << (n % 64)) != 0
+    }
+
+    //Add shift to every amount we can already pay, ie self |= self << shift
+    fn add_shifted(&mut self, shift: usize) {
+        let word_shift = shift / 64;
+        let bit_shift = shift % 64;
+        //Highest first, so the words shifted in haven't been changed yet
+        for i in (word_shift..self.words.len()).rev() {
+            let mut shifted = self.words[i - word_shift] << bit_shift;
+            if bit_shift > 0 && i > word_shift {
+                shifted |= self.words[i - word_shift - 1] >> (64 - bit_shift);
+            }
+            self.words[i] |= shifted;
+        }
+    }
+}
+
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+//Depth first search over the coin types (highest value first) for the best payout
+struct PayoutSearch {
+    credit: u32,
+    strategy: PayoutStrategy,
+    //Coin type index, value and number available - sorted highest value first
+    order: [usize; 16],
+    values: [u32; 16],
+    available: [u8; 16],
+    //Total value of the coins from this position onwards
+    remaining_value: [u32; 17],
+    num_types: usize,
+    current: [u8; 16],
+    best_coins: [u8; 16],
+    best_total: u32,
+    best_coin_count: u16,
+    steps: u32,
+}
+
+impl PayoutSearch {
+    //Returns true once there is no point searching any further
+    fn search(&mut self, pos: usize, remaining: u32, coins_used: u16) -> bool {
+        self.steps += 1;
+        let paid = self.credit - remaining;
+
+        let better = paid > self.best_total
+            || (paid == self.best_total
+                && matches!(self.strategy, PayoutStrategy::FewestCoins)
+                && coins_used < self.best_coin_count);
+        if better {
+            self.best_total = paid;
+            self.best_coin_count = coins_used;
+            self.best_coins = [0; 16];
+            for i in 0..self.num_types {
+                self.best_coins[self.order[i]] = self.current[i];
+            }
+        }
+
+        if remaining == 0 {
+            //Highest value coins are tried first, so the first exact match
+            //preserves the low value coins
+            return matches!(self.strategy, PayoutStrategy::PreserveLowValueCoins);
+        }
+        if pos == self.num_types || self.steps >= MAX_PAYOUT_SEARCH_STEPS {
+            return self.steps >= MAX_PAYOUT_SEARCH_STEPS;
+        }
+        //Can't beat what we already have with the coins that are left
+        if paid + self.remaining_value[pos] < self.best_total {
+            return false;
+        }
+        if matches!(self.strategy, PayoutStrategy::FewestCoins)
+            && self.best_total == self.credit
+            && coins_used + 1 >= self.best_coin_count
+        {
+            return false;
+        }
+
+        let max_count = (remaining / self.values[pos]).min(self.available[pos] as u32) as u8;
+        for count in (0..=max_count).rev() {
+            self.current[pos] = count;
+            if self.search(
+                pos + 1,
+                remaining - self.values[pos] * count as u32,
+                coins_used + count as u16,
+            ) {
+                return true;
+            }
+        }
+        self.current[pos] = 0;
+        false
+    }
 }
 
 #[derive(Format)]
@@ -57,7 +290,7 @@ pub struct CoinAcceptorL3Features {
 
 #[derive(Copy, Clone, Format)]
 pub struct CoinType {
-    pub unscaled_value: u16,
+    pub value: Money,
     pub routeable_to_tube: bool,
     pub tube_full: bool,
     pub num_coins: u8,
@@ -145,7 +378,7 @@ pub enum CoinCassetteErrorSubtype {
 #[derive(Copy, Clone)]
 pub struct CoinInsertedEvent {
     pub coin_type: u8,        //What number coin it is
-    pub unscaled_value: u16,  //Unscaled value
+    pub value: Money,         //Zero if the coin type isn't one we know
     pub routing: CoinRouting, //where it was routed to
     pub coins_remaining: u8,  //what the coin acceptor thinks the tube count now is
 }
@@ -153,7 +386,7 @@ pub struct CoinInsertedEvent {
 #[derive(Copy, Clone)]
 pub struct ManualDispenseEvent {
     pub coin_type: u8,       //type of the coin
-    pub unscaled_value: u16, //unscaled value
+    pub value: Money,        //Of each coin - zero if the coin type isn't one we know
     pub number: u8,          //Number of coins dispensed
     pub coins_remaining: u8, //Remaining coins
 }
@@ -166,6 +399,10 @@ pub enum PollEvent {
     Status(ChangerStatus),
     Coin(CoinInsertedEvent),
     ManualDispense(ManualDispenseEvent),
+    //The changer reset unexpectedly, and we have re-initialised it (true if successful)
+    Reinitialised(bool),
+    //Only sent by L3 changers with FTL enabled
+    Ftl(FtlReply),
 }
 
 #[derive(Format, Copy, Clone)]
@@ -183,13 +420,29 @@ pub enum CoinAcceptorLevel {
 }
 
 impl CoinAcceptor {
-    pub fn init<T: embedded_io::Write + embedded_io::Read>(bus: &mut Mdb<T>) -> Option<Self> {
+    /// currency is the one the machine works in - a changer set up for another is refused
+    pub fn init<T: embedded_io::Write + embedded_io::Read>(
+        bus: &mut Mdb<T>,
+        currency: Currency,
+    ) -> Option<Self> {
         //Start with a reset
         bus.send_data(&[RESET_CMD]);
 
         //Give it 100mS to get over its' reset
         bus.timer.delay_ms(100);
 
+        //Collect its' JUST RESET, so a later poll doesn't think it has reset again
+        let mut buf: [u8; 16] = [0x00; 16];
+        bus.send_data(&[POLL_CMD]);
+        bus.receive_response(&mut buf);
+
+        let coinacceptor = Self::setup(bus)?;
+        currency.check(coinacceptor.currency()).ok()?;
+        Some(coinacceptor)
+    }
+
+    /// Setup, identification and feature enable - everything init does after the reset.
+    fn setup<T: embedded_io::Write + embedded_io::Read>(bus: &mut Mdb<T>) -> Option<Self> {
         //Now send a setup command
         bus.send_data(&[SETUP_CMD]);
 
@@ -212,27 +465,44 @@ impl CoinAcceptor {
                 scaling_factor: buf[3],
                 decimal_places: buf[4],
                 l3_features: None,
+                l3_enabled_features: 0x00,
+                enabled_coins: 0x0000,
+                payout_strategy: PayoutStrategy::PreserveLowValueCoins,
+                payout_timeout_ms: 30000,
+                l3_payout: None,
+                acceptance_policy: None,
+                exact_change_only: false,
+                exact_change_checked: None,
+                out_of_service: false,
                 coin_types: {
-                    //Parse the coin type data
+                    //Parse the coin type data - kept at the coin type's position, as
+                    //everything else (polls, tube status, enable masks) uses that
+                    let format = WireFormat::new(
+                        Currency::from_mdb_code(u16::from_be_bytes([buf[1], buf[2]])),
+                        buf[3],
+                        buf[4],
+                    );
                     let mut types: [Option<CoinType>; 16] = [None; 16];
-                    let mut type_count: usize = 0;
+                    #[allow(clippy::into_iter_on_ref)]
                     for (index, byte) in buf[7..23].into_iter().enumerate() {
                         if *byte != 0x00 {
-                            types[type_count] = Some(CoinType {
-                                unscaled_value: *byte as u16 * buf[3] as u16,
+                            types[index] = Some(CoinType {
+                                value: Money::from_scaled(*byte as u32, format),
                                 tube_full: false,
                                 num_coins: 0,
                                 routeable_to_tube: ((buf[5] as u16) << 8 | buf[6] as u16)
                                     & (0x01 << index)
                                     != 0,
                             });
-                            type_count += 1;
                         }
                     }
 
                     types
                 },
             };
+            let currency = coinacceptor.currency();
+            defmt::debug!("Coin acceptor currency {}", currency);
+            currency::check_decimal_places(currency, coinacceptor.decimal_places);
 
             defmt::debug!("Updating coin counts");
             //Now probe the coin counts and update the above statuses
@@ -278,14 +548,19 @@ impl CoinAcceptor {
                         };
 
                         //Enable the features we want to use
-                        let mut feature_mask: u8 = 0x00;
                         if l3.alt_payout_cmd_supported {
                             features_to_enable |= L3_OPTIONAL_FEATURE::AltPayout as u8;
                         }
                         if l3.ext_diag_cmd_supported {
                             features_to_enable |= L3_OPTIONAL_FEATURE::ExtDiag as u8;
                         }
-                        if coinacceptor.l3_enable_features(bus, feature_mask) {
+                        if l3.controlled_fill_payout_cmd_supported {
+                            features_to_enable |= L3_OPTIONAL_FEATURE::ControlledFillAndPayout as u8;
+                        }
+                        if l3.ftl_cmd_supported {
+                            features_to_enable |= L3_OPTIONAL_FEATURE::Ftl as u8;
+                        }
+                        if coinacceptor.l3_enable_features(bus, features_to_enable) {
                             defmt::debug!("L3 features enabled OK");
                         } else {
                             defmt::debug!("L3 features failed to enable");
@@ -298,7 +573,7 @@ impl CoinAcceptor {
             }
             return Some(coinacceptor);
         }
-        return None;
+        None
     }
 
     pub fn l3_enable_features<T: embedded_io::Write + embedded_io::Read>(
@@ -308,25 +583,115 @@ impl CoinAcceptor {
     ) -> bool {
         if !matches!(self.feature_level, CoinAcceptorLevel::Level3) {
             false
+        } else if bus.send_data_and_confirm_ack(&[
+            L3_CMD_PREFIX,
+            L3_FEATURE_ENABLE_CMD,
+            0x00,
+            0x00,
+            0x00,
+            feature_mask,
+        ]) {
+            self.l3_enabled_features = feature_mask;
+            true
         } else {
-            bus.send_data_and_confirm_ack(&[
-                L3_CMD_PREFIX,
-                L3_FEATURE_ENABLE_CMD,
-                0x00,
-                0x00,
-                0x00,
-                feature_mask,
-            ])
+            false
         }
     }
 
-    fn update_coin_counts<T: embedded_io::Write + embedded_io::Read>(&mut self, bus: &mut Mdb<T>) {
+    /// Switch the controlled manual fill and payout reports on or off, leaving
+    /// the other L3 features as they are
+    pub fn enable_controlled_fill_payout<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        enable: bool,
+    ) -> bool {
+        let supported = self
+            .l3_features
+            .as_ref()
+            .is_some_and(|l3| l3.controlled_fill_payout_cmd_supported);
+        if !supported {
+            defmt::debug!("Coin acceptor does not support controlled fill/payout");
+            return false;
+        }
+        let mask = if enable {
+            self.l3_enabled_features | L3_OPTIONAL_FEATURE::ControlledFillAndPayout as u8
+        } else {
+            self.l3_enabled_features & !(L3_OPTIONAL_FEATURE::ControlledFillAndPayout as u8)
+        };
+        self.l3_enable_features(bus, mask)
+    }
+
+    /// Number of coins of each type put into the tubes through the changer keypad
+    /// since the last report.  The tube counts are updated to match.
+    pub fn controlled_manual_fill_report<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> Option<[u8; 16]> {
+        let report = self.controlled_report(bus, L3_CONTROLLED_FILL_REPORT_CMD)?;
+        for (i, count) in report.iter().enumerate() {
+            if let Some(coin) = self.coin_types[i].as_mut() {
+                coin.num_coins = coin.num_coins.saturating_add(*count);
+            }
+        }
+        Some(report)
+    }
+
+    /// Number of coins of each type paid out through the changer keypad
+    /// since the last report.  The tube counts are updated to match.
+    pub fn controlled_manual_payout_report<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> Option<[u8; 16]> {
+        let report = self.controlled_report(bus, L3_CONTROLLED_PAYOUT_REPORT_CMD)?;
+        for (i, count) in report.iter().enumerate() {
+            if let Some(coin) = self.coin_types[i].as_mut() {
+                coin.num_coins = coin.num_coins.saturating_sub(*count);
+            }
+        }
+        Some(report)
+    }
+
+    fn controlled_report<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        cmd: u8,
+    ) -> Option<[u8; 16]> {
+        if self.l3_enabled_features & L3_OPTIONAL_FEATURE::ControlledFillAndPayout as u8 == 0 {
+            defmt::debug!("Controlled fill/payout not enabled on coin acceptor");
+            return None;
+        }
+        bus.send_data(&[L3_CMD_PREFIX, cmd]);
+
+        let mut buf: [u8; 16] = [0x00; 16];
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(len) => {
+                //One count per coin type - any the changer doesn't send are zero
+                let mut report: [u8; 16] = [0x00; 16];
+                report[0..len].copy_from_slice(&buf[0..len]);
+                Some(report)
+            }
+            MDBResponse::StatusMsg(_) => {
+                defmt::debug!("Coin acceptor failed to send controlled fill/payout report");
+                None
+            }
+        }
+    }
+
+    /// Ask the changer for its' tube status, and update the coin counts and tube full flags
+    pub fn update_coin_counts<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> bool {
         bus.send_data(&[TUBE_STATUS_CMD]);
 
         let mut buf: [u8; 18] = [0x00; 18];
-        let reply_len = bus.receive_response(&mut buf);
+        if !matches!(bus.receive_response(&mut buf), MDBResponse::Data(_)) {
+            //Don't wipe out the counts we have
+            defmt::debug!("Coin acceptor failed to reply with tube status");
+            return false;
+        }
 
-        let tube_full_status: u16 = (buf[0] as u16) << 8 | (buf[1] as u16) << 8;
+        let tube_full_status: u16 = (buf[0] as u16) << 8 | buf[1] as u16;
         //Should get 18 bytes back.
         for i in 0..16 {
             if let Some(mut cointype) = self.coin_types[i].take() {
@@ -335,6 +700,7 @@ impl CoinAcceptor {
                 self.coin_types[i] = Some(cointype);
             }
         }
+        true
     }
 
     pub fn enable_coins<T: embedded_io::Write + embedded_io::Read>(
@@ -343,6 +709,29 @@ impl CoinAcceptor {
         coin_mask: u16,
     ) -> bool {
         //Which coins you want to enable - NB We enable manual dispense for all coins automatically.
+        self.enabled_coins = coin_mask;
+        self.send_coin_enable(bus)
+    }
+
+    /// Stop accepting coins (eg because the changer has a fault), or go back to the
+    /// coins enabled before.  Manual dispense still works while out of service.
+    pub fn set_out_of_service<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        out_of_service: bool,
+    ) -> bool {
+        if out_of_service != self.out_of_service {
+            defmt::info!("Coin acceptor out of service: {}", out_of_service);
+        }
+        self.out_of_service = out_of_service;
+        self.send_coin_enable(bus)
+    }
+
+    fn send_coin_enable<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+    ) -> bool {
+        let coin_mask = if self.out_of_service { 0x0000 } else { self.enabled_coins };
         bus.send_data_and_confirm_ack(&[
             COIN_TYPE_CMD,
             (coin_mask & 0xFF) as u8,
@@ -352,11 +741,170 @@ impl CoinAcceptor {
         ])
     }
 
+    /// Coin types (as an enable mask) with the given value
+    pub fn coin_mask_for_value(&self, value: Money) -> u16 {
+        let mut mask: u16 = 0x0000;
+        for (i, c) in self.coin_types.iter().enumerate() {
+            if let Some(coin) = c {
+                if coin.value == value {
+                    mask |= 0x01 << i;
+                }
+            }
+        }
+        mask
+    }
+
+    /// Accept coins according to the policy from now on.  The coin enable mask
+    /// is recalculated after every poll and payout.
+    pub fn set_acceptance_policy<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        policy: CoinAcceptancePolicy,
+    ) -> bool {
+        self.acceptance_policy = Some(policy);
+        self.apply_acceptance_policy(bus)
+    }
+
+    /// Work out the coin enable mask and exact change state from the policy and tube
+    /// counts, and send the mask to the changer if it has changed.
+    pub fn apply_acceptance_policy<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> bool {
+        let policy = match self.acceptance_policy {
+            Some(policy) => policy,
+            None => return false,
+        };
+
+        let mut mask: u16 = 0x0000;
+        for (i, c) in self.coin_types.iter().enumerate() {
+            if let Some(coin) = c {
+                if coin.value.is_zero() || policy.inhibited_values.contains(&Some(coin.value)) {
+                    continue;
+                }
+                //Nowhere to put it if the tube is full and the cashbox is off limits
+                if !policy.allow_cashbox && (!coin.routeable_to_tube || coin.tube_full) {
+                    continue;
+                }
+                mask |= 0x01 << i;
+            }
+        }
+
+        //The payout search is slow, so only redo the check when the tubes have changed
+        let mut tube_counts: [u8; 16] = [0; 16];
+        for (count, c) in tube_counts.iter_mut().zip(self.coin_types.iter()) {
+            if let Some(coin) = c {
+                *count = coin.num_coins;
+            }
+        }
+        if self.exact_change_checked != Some((tube_counts, policy.change_needed)) {
+            let exact_change_only = self.needs_exact_change(policy.change_needed);
+            if exact_change_only != self.exact_change_only {
+                defmt::info!("Exact change only: {}", exact_change_only);
+                self.exact_change_only = exact_change_only;
+            }
+            self.exact_change_checked = Some((tube_counts, policy.change_needed));
+        }
+
+        if mask != self.enabled_coins {
+            defmt::debug!("Coin enable mask now {=u16:#06x}", mask);
+            self.enable_coins(bus, mask)
+        } else {
+            true
+        }
+    }
+
+    //Whether there is an amount of change, up to change_needed, that the tubes can't pay
+    fn needs_exact_change(&self, change_needed: Option<Money>) -> bool {
+        let change_needed = match change_needed {
+            Some(amount) if !amount.is_zero() => amount,
+            _ => return false,
+        };
+        //Inhibited coins still pay change from their tubes, so they count here
+        let mut smallest_value: u32 = 0;
+        for coin in self.coin_types.iter().flatten() {
+            let value = coin.value.minor_units;
+            if coin.routeable_to_tube && value > 0 && (smallest_value == 0 || value < smallest_value) {
+                smallest_value = value;
+            }
+        }
+        if smallest_value == 0 {
+            return true;
+        }
+
+        //Work in steps of the coin values' common divisor, so the amounts fit in a bitset
+        let mut step = smallest_value;
+        for coin in self.coin_types.iter().flatten() {
+            if coin.num_coins > 0 && !coin.value.is_zero() {
+                step = gcd(step, coin.value.minor_units);
+            }
+        }
+        let mut limit = (change_needed.minor_units / step) as usize;
+        if limit >= MAX_CHANGE_STEPS {
+            defmt::debug!("Change needed is too fine grained - only checking up to {}", step as usize * (MAX_CHANGE_STEPS - 1));
+            limit = MAX_CHANGE_STEPS - 1;
+        }
+
+        //Every amount the tubes can pay, found in one pass.  Each coin type is added in
+        //chunks of 1, 2, 4... coins, which between them make up any number up to its' count.
+        let mut payable = ChangeBitset::new();
+        for coin in self.coin_types.iter().flatten() {
+            if coin.num_coins == 0 || coin.value.is_zero() {
+                continue;
+            }
+            let value = (coin.value.minor_units / step) as usize;
+            let mut remaining = coin.num_coins as usize;
+            let mut chunk = 1;
+            while remaining > 0 {
+                let coins = chunk.min(remaining);
+                if value * coins <= limit {
+                    payable.add_shifted(value * coins);
+                }
+                remaining -= coins;
+                chunk *= 2;
+            }
+        }
+
+        //Check we can pay every amount of change up to what is needed
+        let smallest = (smallest_value / step) as usize;
+        let mut amount = smallest;
+        while amount <= limit {
+            if !payable.get(amount) {
+                return true;
+            }
+            amount += smallest;
+        }
+        false
+    }
+
+    pub fn currency(&self) -> Currency {
+        Currency::from_mdb_code(u16::from_be_bytes(self.country_code))
+    }
+
+    pub fn wire_format(&self) -> WireFormat {
+        WireFormat::new(self.currency(), self.scaling_factor, self.decimal_places)
+    }
+
+    /// Convert an amount to the changer's scaled format, as used by the L3 payout commands.
+    /// None if it is in the wrong currency, isn't a whole number of scaling units, or is too big.
+    pub fn to_wire(&self, amount: Money) -> Option<u8> {
+        u8::try_from(amount.to_scaled(self.wire_format())?).ok()
+    }
+
+    /// Convert a scaled amount from the changer
+    pub fn from_wire(&self, scaled: u8) -> Money {
+        Money::from_scaled(scaled as u32, self.wire_format())
+    }
+
     pub fn payout<T: embedded_io::Write + embedded_io::Read>(
         &mut self,
         bus: &mut Mdb<T>,
-        credit: u16,
-    ) -> u16 {
+        credit: Money,
+    ) -> Money {
+        if credit.currency != self.currency() {
+            defmt::error!("{} is not in the coin acceptor's currency", credit);
+            return Money::zero(self.currency());
+        }
         let use_l3_payout = if let Some(l3features) = &self.l3_features {
             l3features.alt_payout_cmd_supported
         } else {
@@ -380,101 +928,240 @@ impl CoinAcceptor {
         };
         //Update the coin coints
         self.update_coin_counts(bus);
+        if self.acceptance_policy.is_some() {
+            self.apply_acceptance_policy(bus);
+        }
 
         amount_paid
     }
 
+    /// Work out which coins to pay out for a given credit, from what is in the tubes.
+    /// If the credit can't be paid exactly, the plan gets as close as it can without
+    /// overpaying - check plan.total against the credit.
+    pub fn plan_payout(&self, credit: Money) -> PayoutPlan {
+        let currency = self.currency();
+        if credit.currency != currency {
+            defmt::error!("{} is not in the coin acceptor's currency", credit);
+            return PayoutPlan {
+                coins: [0; 16],
+                total: Money::zero(currency),
+            };
+        }
+        let search = self.payout_search(credit.minor_units);
+        if search.steps >= MAX_PAYOUT_SEARCH_STEPS {
+            defmt::debug!("Payout search gave up early - plan may not be optimal");
+        }
+        PayoutPlan {
+            coins: search.best_coins,
+            total: Money::new(search.best_total, currency),
+        }
+    }
+
+    //Run the payout search over the coins in the tubes
+    fn payout_search(&self, credit: u32) -> PayoutSearch {
+        let mut search = PayoutSearch {
+            credit,
+            strategy: self.payout_strategy,
+            order: [0; 16],
+            values: [0; 16],
+            available: [0; 16],
+            remaining_value: [0; 17],
+            num_types: 0,
+            current: [0; 16],
+            best_coins: [0; 16],
+            best_total: 0,
+            best_coin_count: u16::MAX,
+            steps: 0,
+        };
+
+        //Search the highest valued coins first
+        for (i, c) in self.coin_types.iter().enumerate().rev() {
+            if let Some(coin) = c {
+                if coin.num_coins > 0 && !coin.value.is_zero() {
+                    search.order[search.num_types] = i;
+                    search.values[search.num_types] = coin.value.minor_units;
+                    search.available[search.num_types] = coin.num_coins;
+                    search.num_types += 1;
+                }
+            }
+        }
+        //Sort by value, as the coin types aren't guaranteed to be in order
+        for i in 1..search.num_types {
+            let mut j = i;
+            while j > 0 && search.values[j] > search.values[j - 1] {
+                search.order.swap(j, j - 1);
+                search.values.swap(j, j - 1);
+                search.available.swap(j, j - 1);
+                j -= 1;
+            }
+        }
+        for i in (0..search.num_types).rev() {
+            search.remaining_value[i] =
+                search.remaining_value[i + 1] + search.values[i] * search.available[i] as u32;
+        }
+
+        search.search(0, credit, 0);
+        search
+    }
+
+    /// Whether the tubes hold the coins to pay this credit exactly
+    pub fn can_pay_exact(&self, credit: Money) -> bool {
+        self.plan_payout(credit).total == credit
+    }
+
     pub fn payout_level2<T: embedded_io::Write + embedded_io::Read>(
         &mut self,
         bus: &mut Mdb<T>,
-        credit: u16,
-    ) -> u16 {
+        credit: Money,
+    ) -> Money {
         defmt::debug!("Starting Level 2 Payout");
-        let mut amount_paid: u16 = 0;
+        let plan = self.plan_payout(credit);
+        if plan.total != credit {
+            defmt::info!("Cannot pay {} exactly - paying {}", credit, plan.total);
+        }
+
+        let mut amount_paid: u32 = 0;
         //Reverse order, so starting with the highest valued coins first
         for (i, c) in self.coin_types.iter().enumerate().rev() {
             if let Some(coin) = c {
-                let mut num_to_pay = ((credit - amount_paid) / coin.unscaled_value) as u8;
-
-                //Cannot pay out more coins than we have in the tube
-                if num_to_pay as u8 > coin.num_coins {
-                    num_to_pay = coin.num_coins;
-                }
+                let mut num_to_pay = plan.coins[i];
 
                 while num_to_pay > 0 {
-                    //Each command can only pay out 16 coins max, so if we want to
-                    //dispense more than 16, we have to send multiple commands
-                    let num_to_dispense = if num_to_pay > 16 { 16 } else { num_to_pay };
+                    //Each command can only pay out 15 coins max, so if we want to
+                    //dispense more than 15, we have to send multiple commands
+                    let num_to_dispense = if num_to_pay > 15 { 15 } else { num_to_pay };
                     //send the command
-                    let b: u8 = i as u8 | num_to_pay << 4;
+                    let b: u8 = i as u8 | num_to_dispense << 4;
                     defmt::debug!(
-                        "Aiming to dispense {=u8} coins of type {=usize}, value {=u16}",
-                        num_to_pay,
+                        "Aiming to dispense {=u8} coins of type {=usize}, value {}",
+                        num_to_dispense,
                         i,
-                        coin.unscaled_value
+                        coin.value
                     );
-                    if (bus.send_data_and_confirm_ack(&[DISPENSE_CMD, b])) {
+                    if bus.send_data_and_confirm_ack(&[DISPENSE_CMD, b]) {
                         defmt::debug!("Payout cmd acked - payout in progress");
-                        amount_paid += coin.unscaled_value * num_to_pay as u16;
+                        amount_paid += coin.value.minor_units * num_to_dispense as u32;
                         num_to_pay -= num_to_dispense;
                     } else {
-                        defmt::debug!("Payout cmd not acked")
+                        defmt::debug!("Payout cmd not acked");
+                        break;
                     }
                 }
             }
-            if amount_paid == credit {
-                break;
-            }
         }
-        amount_paid
+        Money::new(amount_paid, credit.currency)
     }
 
     pub fn payout_level3<T: embedded_io::Write + embedded_io::Read>(
         &mut self,
         bus: &mut Mdb<T>,
-        credit: u16,
-    ) -> u16 {
+        credit: Money,
+    ) -> Money {
+        if !self.payout_level3_start(bus, credit) {
+            return Money::zero(credit.currency);
+        }
+        loop {
+            match self.payout_level3_poll(bus) {
+                PayoutProgress::Paying(_) => bus.timer.delay_ms(L3_PAYOUT_POLL_INTERVAL_MS),
+                PayoutProgress::Complete(paid) | PayoutProgress::Incomplete(paid) => return paid,
+            }
+        }
+    }
+
+    /// Start an L3 payout, without waiting for it to finish.  Call payout_level3_poll
+    /// until it stops returning PayoutProgress::Paying to follow its' progress.
+    pub fn payout_level3_start<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        credit: Money,
+    ) -> bool {
         defmt::debug!("Starting Level 3 Payout");
-        let credit_scaled = credit / self.scaling_factor as u16;
-        if credit_scaled > 255 {
-            defmt::debug!("Payout value exceeds allowable limit");
-            0
-        } else {
-            bus.send_data_and_confirm_ack(&[L3_CMD_PREFIX, L3_PAYOUT_CMD, credit_scaled as u8]);
+        //The changer can only pay whole scaling units - anything less stays as credit
+        let credit_scaled = match credit
+            .to_scaled_floor(self.wire_format())
+            .and_then(|scaled| u8::try_from(scaled).ok())
+        {
+            Some(scaled) => scaled,
+            None => {
+                defmt::debug!("Payout value exceeds allowable limit");
+                return false;
+            }
+        };
+        if !bus.send_data_and_confirm_ack(&[L3_CMD_PREFIX, L3_PAYOUT_CMD, credit_scaled]) {
+            defmt::debug!("Payout cmd not acked");
+            return false;
+        }
+        self.l3_payout = Some(L3PayoutState {
+            started_at: bus.timer.get_counter().ticks(),
+            paid_so_far: Money::zero(credit.currency),
+        });
+        true
+    }
 
-            let mut buf: [u8; 16] = [0x00; 16];
-            let mut complete: bool = false;
+    /// Ask the changer how much of the L3 payout it has paid so far.  If the changer
+    /// doesn't finish before payout_timeout_ms, we give up and use the payout status
+    /// to find out how much it did manage to pay.
+    pub fn payout_level3_poll<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> PayoutProgress {
+        let mut state = match self.l3_payout {
+            Some(state) => state,
+            None => {
+                defmt::debug!("No L3 payout in progress");
+                return PayoutProgress::Complete(Money::zero(self.currency()));
+            }
+        };
 
-            while !complete {
-                bus.send_data(&[L3_CMD_PREFIX, L3_PAYOUT_VALUE_POLL_CMD]);
-                match bus.receive_response(&mut buf) {
-                    MDBResponse::Data(count) => {
-                        //This is the amount of credit paid out so far, not that interested for now
-                    }
-                    MDBResponse::StatusMsg(x) => match x {
-                        MDBStatus::ACK => {
-                            complete = true;
-                        }
-                        _ => {}
-                    },
-                }
+        let mut buf: [u8; 16] = [0x00; 16];
+        bus.send_data(&[L3_CMD_PREFIX, L3_PAYOUT_VALUE_POLL_CMD]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count > 0 => {
+                //This is the (scaled) amount of credit paid out so far
+                state.paid_so_far = self.from_wire(buf[0]);
+                self.l3_payout = Some(state);
+            }
+            MDBResponse::StatusMsg(MDBStatus::ACK) => {
+                //Payout finished
+                self.l3_payout = None;
+                let paid = self.l3_payout_status(bus).unwrap_or(state.paid_so_far);
+                return PayoutProgress::Complete(paid);
             }
-            let mut amount_paid: u16 = 0;
+            _ => {}
+        }
+
+        let elapsed_us = bus.timer.get_counter().ticks().saturating_sub(state.started_at);
+        if elapsed_us >= self.payout_timeout_ms as u64 * 1000 {
+            defmt::error!("Coin acceptor payout timed out");
+            self.l3_payout = None;
+            let paid = self.l3_payout_status(bus).unwrap_or(state.paid_so_far);
+            return PayoutProgress::Incomplete(paid);
+        }
+        PayoutProgress::Paying(state.paid_so_far)
+    }
 
-            bus.send_data(&[L3_CMD_PREFIX, L3_PAYOUT_STATUS_CMD]);
-            match bus.receive_response(&mut buf) {
-                MDBResponse::Data(count) => {
-                    for (i, byte) in buf[0..count].iter().enumerate() {
-                        self.coin_types[i].and_then(|ct| {
-                            amount_paid += ct.unscaled_value * *byte as u16;
-                            Some(ct)
-                        });
+    /// Value of the coins paid out by the last L3 payout, if the changer will tell us
+    fn l3_payout_status<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> Option<Money> {
+        let mut buf: [u8; 16] = [0x00; 16];
+        bus.send_data(&[L3_CMD_PREFIX, L3_PAYOUT_STATUS_CMD]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(count) => {
+                let mut amount_paid: u32 = 0;
+                for (i, byte) in buf[0..count].iter().enumerate() {
+                    if let Some(ct) = self.coin_types[i] {
+                        amount_paid += ct.value.minor_units * *byte as u32;
                     }
                 }
-                _ => {}
+                Some(Money::new(amount_paid, self.currency()))
+            }
+            MDBResponse::StatusMsg(_) => {
+                //An ACK here means the changer is still busy paying out
+                defmt::debug!("Coin acceptor did not report payout status");
+                None
             }
-
-            amount_paid
         }
     }
 
@@ -482,126 +1169,212 @@ impl CoinAcceptor {
         &mut self,
         bus: &mut Mdb<T>,
     ) -> [Option<PollEvent>; 16] {
-        //You might get up to 16 poll events and you should process them in order..
-        let mut poll_results: [Option<PollEvent>; 16] = [None; 16];
-        let mut result_count: usize = 0;
-
         //Send poll command
         bus.send_data(&[POLL_CMD]);
 
-        //Read poll response - max 16 bytes
-        let mut buf: [u8; 16] = [0x00; 16];
+        //Read poll response - max 16 bytes, unless it is an FTL reply
+        let mut buf: [u8; 36] = [0x00; 36];
 
-        //Parse response
-        match bus.receive_response(&mut buf) {
-            MDBResponse::StatusMsg(status) => {
-                if matches!(status, MDBStatus::ACK) {
-                    //nothing to report;
+        //You might get up to 16 poll events and you should process them in order..
+        let mut poll_results: [Option<PollEvent>; 16] = [None; 16];
+        if let MDBResponse::Data(count) = bus.receive_response(&mut buf) {
+            poll_results = self.parse_poll_reply(&buf[..count]);
+        }
+
+        for result in poll_results.iter_mut() {
+            match result {
+                //If the changer has reset behind our back (eg a brownout), it will have lost its'
+                //setup, so re-initialise it and let the application know
+                Some(PollEvent::Status(ChangerStatus::ChangerWasReset)) => {
+                    *result = Some(PollEvent::Reinitialised(self.reinitialise(bus)));
                 }
-            }
-            MDBResponse::Data(count) => {
-                //small state machine to handle 2 byte nature of potential messages.
-                enum ParseState {
-                    ManualDispense(u8),
-                    CoinDeposited(u8),
-                    NoState,
+                //Keep the tube counts up to date with what the changer tells us
+                Some(PollEvent::Coin(coin)) if matches!(coin.routing, CoinRouting::Tube) => {
+                    if let Some(ct) = self.coin_types[coin.coin_type as usize].as_mut() {
+                        ct.num_coins = coin.coins_remaining;
+                    }
                 }
-                let mut state: ParseState = ParseState::NoState;
-
-                for byte in &buf[0..count] {
-                    match state {
-                        ParseState::NoState => {
-                            if byte & 0x80 == 0x80 {
-                                //Enter manual dispense paree, and wait for byte 2 to arrive
-                                state = ParseState::ManualDispense(*byte);
-                            } else if byte & 0x40 == 0x40 {
-                                //Enter coin deposited state, and wait for byte 2 to arrive
-                                state = ParseState::CoinDeposited(*byte);
-                            } else if byte & 0x20 == 0x20 {
-                                //FYI: Slugs are 'items' not recognised as valid coins
-                                //US English term apparently - eg a washer to try to fool the acceptor.
+                Some(PollEvent::ManualDispense(dispense)) => {
+                    if let Some(ct) = self.coin_types[dispense.coin_type as usize].as_mut() {
+                        ct.num_coins = dispense.coins_remaining;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        //Tube counts may have changed, so the coins we accept might need to as well
+        if self.acceptance_policy.is_some() && poll_results[0].is_some() {
+            //Only the tube status tells us if a tube has filled up
+            let coin_to_tube = poll_results.iter().any(|r| {
+                matches!(r, Some(PollEvent::Coin(coin)) if matches!(coin.routing, CoinRouting::Tube))
+            });
+            if coin_to_tube {
+                self.update_coin_counts(bus);
+            }
+            self.apply_acceptance_policy(bus);
+        }
+
+        poll_results
+    }
+
+    //Split a poll reply into events - coin and manual dispense events take two bytes
+    fn parse_poll_reply(&self, reply: &[u8]) -> [Option<PollEvent>; 16] {
+        let mut poll_results: [Option<PollEvent>; 16] = [None; 16];
+        let mut result_count: usize = 0;
+
+        //small state machine to handle 2 byte nature of potential messages.
+        enum ParseState {
+            ManualDispense(u8),
+            CoinDeposited(u8),
+            NoState,
+        }
+        let mut state: ParseState = ParseState::NoState;
+
+        for (index, byte) in reply.iter().enumerate() {
+            if result_count == poll_results.len() {
+                defmt::debug!("Too many events in poll reply - the rest are dropped");
+                break;
+            }
+            match state {
+                ParseState::NoState => {
+                    if byte & 0x80 == 0x80 {
+                        //Enter manual dispense paree, and wait for byte 2 to arrive
+                        state = ParseState::ManualDispense(*byte);
+                    } else if byte & 0x40 == 0x40 {
+                        //Enter coin deposited state, and wait for byte 2 to arrive
+                        state = ParseState::CoinDeposited(*byte);
+                    } else if byte & 0x20 == 0x20 {
+                        //FYI: Slugs are 'items' not recognised as valid coins
+                        //US English term apparently - eg a washer to try to fool the acceptor.
+                        poll_results[result_count] =
+                            Some(PollEvent::SlugCount(byte & 0x1F));
+                        result_count += 1;
+                    } else if ftl::is_ftl_reply(*byte) {
+                        //An FTL reply takes up the rest of the message
+                        match ftl::parse_reply(&reply[index..]) {
+                            Some(reply) => {
+                                poll_results[result_count] = Some(PollEvent::Ftl(reply));
+                            }
+                            None => defmt::debug!("Invalid FTL reply received in poll"),
+                        }
+                        break;
+                    } else {
+                        match ChangerStatus::n(*byte) {
+                            Some(status) => {
                                 poll_results[result_count] =
-                                    Some(PollEvent::SlugCount(byte & 0x1F));
+                                    Some(PollEvent::Status(status));
                                 result_count += 1;
+                            }
+                            None => {
+                                defmt::debug!("Unrecognised status byte received in poll")
+                            }
+                        }
+                    };
+                }
+                ParseState::CoinDeposited(b) => {
+                    ////Someone has deposited a coin
+                    poll_results[result_count] = Some(PollEvent::Coin(CoinInsertedEvent {
+                        coin_type: b & 0x0F,
+                        value: {
+                            if let Some(ct) = self.coin_types[(b & 0x0F) as usize] {
+                                ct.value
                             } else {
-                                match ChangerStatus::n(*byte) {
-                                    Some(status) => {
-                                        poll_results[result_count] =
-                                            Some(PollEvent::Status(status));
-                                        result_count += 1;
-                                    }
-                                    None => {
-                                        defmt::debug!("Unrecognised status byte received in poll")
-                                    }
+                                defmt::debug!("Non existent coin deposited!");
+                                Money::zero(self.currency())
+                            }
+                        },
+                        routing: {
+                            match b & 0x30 {
+                                0x00 => CoinRouting::CashBox,
+                                0x10 => CoinRouting::Tube,
+                                0x30 => CoinRouting::Reject,
+                                _ => {
+                                    // shouldn't happen...
+                                    CoinRouting::Unknown
                                 }
-                            };
-                        }
-                        ParseState::CoinDeposited(b) => {
-                            ////Someone has deposited a coin
-                            poll_results[result_count] = Some(PollEvent::Coin(CoinInsertedEvent {
-                                coin_type: b & 0x0F,
-                                unscaled_value: {
-                                    if let Some(ct) = self.coin_types[(b & 0x0F) as usize] {
-                                        ct.unscaled_value
-                                    } else {
-                                        defmt::debug!("Non existent coin deposited!");
-                                        0
-                                    }
-                                },
-                                // * self.scaling_factor as u16,
-                                routing: {
-                                    match b & 0x30 {
-                                        0x00 => CoinRouting::CashBox,
-                                        0x10 => CoinRouting::Tube,
-                                        0x30 => CoinRouting::Reject,
-                                        _ => {
-                                            // shouldn't happen...
-                                            CoinRouting::Unknown
-                                        }
-                                    }
-                                },
-                                coins_remaining: *byte,
-                            }));
-                            result_count += 1;
+                            }
+                        },
+                        coins_remaining: *byte,
+                    }));
+                    result_count += 1;
 
-                            //Reset the state machine
-                            state = ParseState::NoState;
-                        }
-                        ParseState::ManualDispense(b) => {
-                            poll_results[result_count] =
-                                Some(PollEvent::ManualDispense(ManualDispenseEvent {
-                                    coin_type: b & 0x0F,
-                                    unscaled_value: {
-                                        if let Some(ct) = self.coin_types[(b & 0x0F) as usize] {
-                                            ct.unscaled_value
-                                        } else {
-                                            defmt::debug!("Non existent coin manually dispensed!");
-                                            0
-                                        }
-                                    },
-                                    number: (b >> 4) & 0x07,
-                                    coins_remaining: *byte,
-                                }));
-                            result_count += 1;
-                            //Reset the state machine
-                            state = ParseState::NoState;
-                        }
-                    }
+                    //Reset the state machine
+                    state = ParseState::NoState;
+                }
+                ParseState::ManualDispense(b) => {
+                    poll_results[result_count] =
+                        Some(PollEvent::ManualDispense(ManualDispenseEvent {
+                            coin_type: b & 0x0F,
+                            value: {
+                                if let Some(ct) = self.coin_types[(b & 0x0F) as usize] {
+                                    ct.value
+                                } else {
+                                    defmt::debug!("Non existent coin manually dispensed!");
+                                    Money::zero(self.currency())
+                                }
+                            },
+                            number: (b >> 4) & 0x07,
+                            coins_remaining: *byte,
+                        }));
+                    result_count += 1;
+                    //Reset the state machine
+                    state = ParseState::NoState;
                 }
             }
         }
-
         poll_results
     }
 
+    fn reinitialise<T: embedded_io::Write + embedded_io::Read>(&mut self, bus: &mut Mdb<T>) -> bool {
+        defmt::info!("Coin acceptor reset unexpectedly - re-initialising");
+        match Self::setup(bus) {
+            Some(coinacceptor) => {
+                //Don't switch currency behind the application's back
+                if self.currency().check(coinacceptor.currency()).is_err() {
+                    return false;
+                }
+                let enabled_coins = self.enabled_coins;
+                let payout_strategy = self.payout_strategy;
+                let payout_timeout_ms = self.payout_timeout_ms;
+                let acceptance_policy = self.acceptance_policy;
+                let out_of_service = self.out_of_service;
+                *self = coinacceptor;
+                self.payout_strategy = payout_strategy;
+                self.payout_timeout_ms = payout_timeout_ms;
+                self.acceptance_policy = acceptance_policy;
+                self.out_of_service = out_of_service;
+                if self.acceptance_policy.is_some() {
+                    self.apply_acceptance_policy(bus)
+                } else {
+                    self.enable_coins(bus, enabled_coins)
+                }
+            }
+            None => {
+                defmt::error!("Coin acceptor failed to re-initialise");
+                false
+            }
+        }
+    }
+
+    /// Whether l3_diagnostic_status can be used.  SEND DIAG STATUS is standard on every L3
+    /// changer - it's only the extended diagnostics command that is optional.
+    pub fn supports_diagnostics(&self) -> bool {
+        matches!(self.feature_level, CoinAcceptorLevel::Level3)
+    }
+
     pub fn l3_diagnostic_status<T: embedded_io::Write + embedded_io::Read>(
         &mut self,
         bus: &mut Mdb<T>,
     ) -> [Option<L3ChangerStatus>; 8] {
-        //Fixme - we should check we are a l3 changer prior to sending this command....
         let mut statuses: [Option<L3ChangerStatus>; 8] = [None; 8];
         let mut num_statuses: usize = 0;
 
+        if !self.supports_diagnostics() {
+            defmt::debug!("Coin acceptor does not support L3 diagnostics");
+            return statuses;
+        }
+
         bus.send_data(&[L3_CMD_PREFIX, L3_DIAG_CMD]);
 
         let mut buf: [u8; 16] = [0x00; 16];
@@ -626,6 +1399,10 @@ impl CoinAcceptor {
                                 0x02 => Some(L3ChangerStatus::PoweringDown),
                                 0x03 => Some(L3ChangerStatus::Ok),
                                 0x04 => Some(L3ChangerStatus::KeypadShifted),
+                                0x05 => match *byte {
+                                    0x20 => Some(L3ChangerStatus::NewInventoryInfoAvailable),
+                                    _ => Some(L3ChangerStatus::ManualFillOrPayoutActive),
+                                },
                                 0x06 => Some(L3ChangerStatus::InhibitedByVmc),
                                 0x10 => {
                                     if let Some(suberror) = GeneralErrorSubtype::n(*byte) {
@@ -708,11 +1485,187 @@ impl CoinAcceptor {
                     }
                 }
             }
-            MDBResponse::StatusMsg(msg) => {
+            MDBResponse::StatusMsg(_) => {
                 //Nothing to do - I don't think this is a valid response
             }
         }
 
+        //The changer's tube counts have changed (eg a manual fill), so fetch them
+        if statuses
+            .iter()
+            .any(|s| matches!(s, Some(L3ChangerStatus::NewInventoryInfoAvailable)))
+        {
+            self.update_coin_counts(bus);
+        }
+
         statuses
     }
 }
+
+impl<T: embedded_io::Write + embedded_io::Read> FtlDevice<Mdb<T>> for CoinAcceptor {
+    fn ftl_address(&self) -> u8 {
+        COIN_ACCEPTOR_ADDRESS
+    }
+
+    fn ftl_command_prefix(&self) -> u8 {
+        L3_CMD_PREFIX
+    }
+
+    fn ftl_poll(&mut self, bus: &mut Mdb<T>) -> Option<FtlReply> {
+        for event in self.poll(bus).into_iter().flatten() {
+            match event {
+                PollEvent::Ftl(reply) => return Some(reply),
+                _ => defmt::debug!("Ignoring coin acceptor poll event during FTL transfer"),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    const EUR: Currency = Currency::Iso(978);
+
+    //A euro changer with the given coin values (in cents) and tube counts
+    fn changer(coins: &[(u32, u8)]) -> CoinAcceptor {
+        let mut coin_types: [Option<CoinType>; 16] = [None; 16];
+        for (i, (value, count)) in coins.iter().enumerate() {
+            coin_types[i] = Some(CoinType {
+                value: Money::new(*value, EUR),
+                routeable_to_tube: true,
+                tube_full: false,
+                num_coins: *count,
+            });
+        }
+        CoinAcceptor {
+            feature_level: CoinAcceptorLevel::Level2,
+            country_code: [0x19, 0x78],
+            scaling_factor: 5,
+            decimal_places: 2,
+            coin_types,
+            l3_features: None,
+            l3_enabled_features: 0x00,
+            enabled_coins: 0x0000,
+            payout_strategy: PayoutStrategy::PreserveLowValueCoins,
+            payout_timeout_ms: 30000,
+            l3_payout: None,
+            acceptance_policy: None,
+            exact_change_only: false,
+            exact_change_checked: None,
+            out_of_service: false,
+        }
+    }
+
+    #[test]
+    fn poll_reply_events() {
+        let acceptor = changer(&[(10, 0), (20, 0)]);
+        //Status, slugs, a 20c coin to the tube (3 left), then a manual dispense of 2 x 10c
+        let events = acceptor.parse_poll_reply(&[0x10, 0x21, 0x51, 0x03, 0xA0, 0x05]);
+        assert!(matches!(events[0], Some(PollEvent::Status(ChangerStatus::ChangerBusy))));
+        assert!(matches!(events[1], Some(PollEvent::SlugCount(1))));
+        match events[2] {
+            Some(PollEvent::Coin(coin)) => {
+                assert_eq!(coin.coin_type, 1);
+                assert_eq!(coin.value, Money::new(20, EUR));
+                assert!(matches!(coin.routing, CoinRouting::Tube));
+                assert_eq!(coin.coins_remaining, 3);
+            }
+            _ => panic!("expected a coin"),
+        }
+        match events[3] {
+            Some(PollEvent::ManualDispense(dispense)) => {
+                assert_eq!(dispense.coin_type, 0);
+                assert_eq!(dispense.number, 2);
+                assert_eq!(dispense.coins_remaining, 5);
+            }
+            _ => panic!("expected a manual dispense"),
+        }
+        assert!(events[4].is_none());
+    }
+
+    #[test]
+    fn over_long_poll_reply_is_truncated() {
+        let acceptor = changer(&[(10, 0)]);
+        //20 slug reports - more events than fit
+        let events = acceptor.parse_poll_reply(&[0x21; 20]);
+        assert!(events.iter().all(|e| matches!(e, Some(PollEvent::SlugCount(1)))));
+    }
+
+    #[test]
+    fn exact_change_needed() {
+        let needed = Some(Money::new(100, EUR));
+        assert!(!changer(&[(10, 10), (50, 2)]).needs_exact_change(needed));
+        //Every multiple of 20 can be made from 20s and 50s...
+        assert!(!changer(&[(20, 10), (50, 2)]).needs_exact_change(needed));
+        //...but not 40 with only one 20
+        assert!(changer(&[(20, 1), (50, 2)]).needs_exact_change(needed));
+        //Only 50 cents in 10s, so 60 is fine but 90 can't be paid
+        assert!(changer(&[(10, 5), (100, 2)]).needs_exact_change(needed));
+        //Empty tubes
+        assert!(changer(&[(10, 0), (50, 0)]).needs_exact_change(needed));
+        assert!(!changer(&[(10, 0)]).needs_exact_change(None));
+    }
+
+    #[test]
+    fn change_bitset_shifts_across_words() {
+        let mut bits = ChangeBitset::new();
+        bits.add_shifted(60);
+        bits.add_shifted(70);
+        assert!(bits.get(60) && bits.get(70) && bits.get(130));
+        assert!(!bits.get(64) && !bits.get(129));
+    }
+
+    #[test]
+    fn payout_needs_more_than_greedy() {
+        //Greedy would take the 50 and then be stuck
+        let plan = changer(&[(20, 5), (50, 2)]).plan_payout(Money::new(60, EUR));
+        assert_eq!(plan.total, Money::new(60, EUR));
+        assert_eq!(plan.coins[..2], [3, 0]);
+    }
+
+    #[test]
+    fn payout_from_empty_tubes() {
+        let plan = changer(&[(10, 0), (50, 0)]).plan_payout(Money::new(60, EUR));
+        assert!(plan.total.is_zero());
+        assert_eq!(plan.coins, [0; 16]);
+    }
+
+    #[test]
+    fn partial_payout_never_overpays() {
+        let acceptor = changer(&[(20, 1), (50, 1)]);
+        let plan = acceptor.plan_payout(Money::new(60, EUR));
+        assert_eq!(plan.total, Money::new(50, EUR));
+        assert_eq!(plan.coins[..2], [0, 1]);
+        assert!(!acceptor.can_pay_exact(Money::new(60, EUR)));
+        assert!(acceptor.can_pay_exact(Money::new(70, EUR)));
+    }
+
+    #[test]
+    fn payout_strategies() {
+        let mut acceptor = changer(&[(5, 10), (10, 10), (20, 2), (25, 2)]);
+        let plan = acceptor.plan_payout(Money::new(40, EUR));
+        assert_eq!(plan.coins[..4], [1, 1, 0, 1]);
+
+        acceptor.payout_strategy = PayoutStrategy::FewestCoins;
+        let plan = acceptor.plan_payout(Money::new(40, EUR));
+        assert_eq!(plan.coins[..4], [0, 0, 2, 0]);
+        assert_eq!(plan.total, Money::new(40, EUR));
+    }
+
+    #[test]
+    fn payout_search_gives_up() {
+        //Only even values, so an odd amount is never paid exactly and the search can't stop early
+        let coins: [(u32, u8); 16] = core::array::from_fn(|i| (2 * (i as u32 + 1), 255));
+        let acceptor = changer(&coins);
+        let search = acceptor.payout_search(10001);
+        assert_eq!(search.steps, MAX_PAYOUT_SEARCH_STEPS);
+        assert_eq!(search.best_total, 10000);
+
+        let plan = acceptor.plan_payout(Money::new(10001, EUR));
+        let total: u32 = coins.iter().zip(plan.coins).map(|((value, _), n)| value * n as u32).sum();
+        assert_eq!(plan.total, Money::new(total, EUR));
+    }
+}
@@ -0,0 +1,103 @@
+use crate::coin_acceptor::{ChangerStatus, CoinAcceptor, CoinRouting, PollEvent};
+use crate::currency::Currency;
+use crate::money::Money;
+use crate::Mdb;
+
+use defmt::Format;
+
+//Holds the credit built up from coins inserted by the customer, and gives it back
+//when they press the escrow (coin return) lever.  Feed it each event from
+//CoinAcceptor::poll.
+
+#[derive(Copy, Clone, Format)]
+pub enum CoinCreditEvent {
+    //Value of the coin, and the credit now held
+    CoinAccepted(Money, Money),
+    Refunded(Refund),
+}
+
+/// Outcome of giving the customer's credit back
+#[derive(Copy, Clone, Format)]
+pub struct Refund {
+    pub paid: Money,
+    //What we couldn't pay out - this is still held as credit
+    pub remainder: Money,
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct CoinCredit {
+    pub credit: Money,
+}
+
+impl CoinCredit {
+    /// currency as given by CoinAcceptor::currency
+    pub fn new(currency: Currency) -> Self {
+        Self {
+            credit: Money::zero(currency),
+        }
+    }
+
+    /// Handle a poll event from the coin acceptor - coins add to the credit, and
+    /// pressing the escrow lever refunds it.
+    pub fn handle_event<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        acceptor: &mut CoinAcceptor,
+        bus: &mut Mdb<T>,
+        event: &PollEvent,
+    ) -> Option<CoinCreditEvent> {
+        match event {
+            PollEvent::Coin(coin) => {
+                if matches!(coin.routing, CoinRouting::Reject) || coin.value.is_zero() {
+                    return None;
+                }
+                let value = coin.value;
+                self.credit = self.credit.saturating_add(value)?;
+                Some(CoinCreditEvent::CoinAccepted(value, self.credit))
+            }
+            PollEvent::Status(ChangerStatus::EscrowPressed) => {
+                defmt::debug!("Escrow lever pressed");
+                if self.credit.is_zero() {
+                    None
+                } else {
+                    Some(CoinCreditEvent::Refunded(self.refund(acceptor, bus)))
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Pay the credit back to the customer.  Anything the tubes can't pay is kept as credit.
+    pub fn refund<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        acceptor: &mut CoinAcceptor,
+        bus: &mut Mdb<T>,
+    ) -> Refund {
+        if !acceptor.can_pay_exact(self.credit) {
+            defmt::info!("Cannot refund credit of {} exactly", self.credit);
+        }
+        let mut paid = acceptor.payout(bus, self.credit);
+        if !self.credit.covers(paid) {
+            paid = self.credit;
+        }
+        self.credit = self.credit.saturating_sub(paid).unwrap_or(self.credit);
+        if !self.credit.is_zero() {
+            defmt::info!("Unable to refund {} - kept as credit", self.credit);
+        }
+        Refund {
+            paid,
+            remainder: self.credit,
+        }
+    }
+
+    /// Take the price of a vend from the credit.  Returns false (and leaves the credit
+    /// alone) if there isn't enough.
+    pub fn deduct(&mut self, amount: Money) -> bool {
+        match self.credit.checked_sub(amount) {
+            Some(credit) => {
+                self.credit = credit;
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use crate::coin_acceptor::{
+    AcceptGateErrorSubtype, CoinAcceptor, CoinCassetteErrorSubtype, DiscriminatorErrorSubtype,
+    L3ChangerStatus, SeparatorModuleErrorSubtype,
+};
+use crate::Mdb;
+
+use defmt::Format;
+
+//Checks the changer's L3 diagnostic status every so often, and keeps track of which fault
+//conditions are current, so the application gets a single event when each is raised and
+//when it clears.  Optionally the changer is taken out of coin service while any alarm is
+//raised.  Level 2 changers can't report these, so are left alone.
+
+//Default time between diagnostic checks
+const DEFAULT_CHECK_INTERVAL_MS: u32 = 5000;
+
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum ChangerAlarm {
+    CoinJam = 0x01,
+    CassetteRemoved = 0x02,
+    FlightDeckOpen = 0x04,
+    SensorError = 0x08,
+}
+
+const ALL_ALARMS: [ChangerAlarm; 4] = [
+    ChangerAlarm::CoinJam,
+    ChangerAlarm::CassetteRemoved,
+    ChangerAlarm::FlightDeckOpen,
+    ChangerAlarm::SensorError,
+];
+
+#[derive(Copy, Clone, Format)]
+pub enum HealthEvent {
+    Raised(ChangerAlarm),
+    Cleared(ChangerAlarm),
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct ChangerHealthMonitor {
+    pub check_interval_ms: u32,
+    //Take the changer out of coin service while any alarm is raised
+    pub disable_on_alarm: bool,
+    //Alarms currently raised, as a mask of ChangerAlarm
+    pub active_alarms: u8,
+    last_check: Option<u64>, //Timer counter, uS
+}
+
+impl ChangerHealthMonitor {
+    pub fn new() -> Self {
+        Self {
+            check_interval_ms: DEFAULT_CHECK_INTERVAL_MS,
+            disable_on_alarm: true,
+            active_alarms: 0x00,
+            last_check: None,
+        }
+    }
+
+    pub fn check_interval_ms(mut self, interval: u32) -> Self {
+        self.check_interval_ms = interval;
+        self
+    }
+
+    pub fn disable_on_alarm(mut self, disable: bool) -> Self {
+        self.disable_on_alarm = disable;
+        self
+    }
+
+    pub fn is_raised(&self, alarm: ChangerAlarm) -> bool {
+        self.active_alarms & alarm as u8 != 0
+    }
+
+    pub fn healthy(&self) -> bool {
+        self.active_alarms == 0x00
+    }
+
+    /// Call from the main loop - checks the changer's diagnostics if the interval has
+    /// passed since the last check.  Returns an event for each alarm raised or cleared.
+    pub fn poll<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        acceptor: &mut CoinAcceptor,
+        bus: &mut Mdb<T>,
+    ) -> [Option<HealthEvent>; 4] {
+        let now = bus.timer.get_counter().ticks();
+        if let Some(last) = self.last_check {
+            if now.saturating_sub(last) < self.check_interval_ms as u64 * 1000 {
+                return [None; 4];
+            }
+        }
+        self.last_check = Some(now);
+        self.check(acceptor, bus)
+    }
+
+    /// Check the changer's diagnostics now
+    pub fn check<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        acceptor: &mut CoinAcceptor,
+        bus: &mut Mdb<T>,
+    ) -> [Option<HealthEvent>; 4] {
+        let mut events: [Option<HealthEvent>; 4] = [None; 4];
+        if !acceptor.supports_diagnostics() {
+            return events;
+        }
+
+        let statuses = acceptor.l3_diagnostic_status(bus);
+        if statuses.iter().all(|s| s.is_none()) {
+            //No reply - don't clear anything on the strength of that
+            defmt::debug!("No diagnostic status from coin acceptor");
+            return events;
+        }
+
+        let mut alarms: u8 = 0x00;
+        for alarm in statuses.iter().flatten().filter_map(alarm_for_status) {
+            alarms |= alarm as u8;
+        }
+
+        for (i, alarm) in ALL_ALARMS.iter().enumerate() {
+            let was_raised = self.is_raised(*alarm);
+            let now_raised = alarms & *alarm as u8 != 0;
+            if now_raised && !was_raised {
+                defmt::info!("Coin acceptor alarm raised: {}", alarm);
+                events[i] = Some(HealthEvent::Raised(*alarm));
+            } else if was_raised && !now_raised {
+                defmt::info!("Coin acceptor alarm cleared: {}", alarm);
+                events[i] = Some(HealthEvent::Cleared(*alarm));
+            }
+        }
+
+        let was_healthy = self.healthy();
+        self.active_alarms = alarms;
+        if self.disable_on_alarm && was_healthy != self.healthy() {
+            acceptor.set_out_of_service(bus, !self.healthy());
+        }
+        events
+    }
+}
+
+impl Default for ChangerHealthMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn alarm_for_status(status: &L3ChangerStatus) -> Option<ChangerAlarm> {
+    match status {
+        L3ChangerStatus::DiscriminatorError(DiscriminatorErrorSubtype::FlightDeckOpen) => {
+            Some(ChangerAlarm::FlightDeckOpen)
+        }
+        L3ChangerStatus::DiscriminatorError(DiscriminatorErrorSubtype::CoinJam)
+        | L3ChangerStatus::DiscriminatorError(DiscriminatorErrorSubtype::EscrowReturnStuck)
+        | L3ChangerStatus::AcceptGateError(AcceptGateErrorSubtype::CoinsDidNotExit)
+        | L3ChangerStatus::DispenserError => Some(ChangerAlarm::CoinJam),
+        L3ChangerStatus::CoinCassetteError(CoinCassetteErrorSubtype::CassetteRemoved) => {
+            Some(ChangerAlarm::CassetteRemoved)
+        }
+        L3ChangerStatus::DiscriminatorError(DiscriminatorErrorSubtype::ValSensorAErr)
+        | L3ChangerStatus::DiscriminatorError(DiscriminatorErrorSubtype::ValSensorBErr)
+        | L3ChangerStatus::DiscriminatorError(DiscriminatorErrorSubtype::ValSensorCErr)
+        | L3ChangerStatus::DiscriminatorError(DiscriminatorErrorSubtype::OpticsFailure)
+        | L3ChangerStatus::AcceptGateError(AcceptGateErrorSubtype::PostGateSensorCovered)
+        | L3ChangerStatus::SeparatorError(SeparatorModuleErrorSubtype::SortSensor)
+        | L3ChangerStatus::CoinCassetteError(CoinCassetteErrorSubtype::CashBoxSensorError)
+        | L3ChangerStatus::CoinCassetteError(CoinCassetteErrorSubtype::SunlightOnSensors) => {
+            Some(ChangerAlarm::SensorError)
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,524 @@
+use crate::coin_acceptor::CoinAcceptor;
+use crate::currency;
+use crate::currency::Currency;
+use crate::money::{Money, WireFormat};
+use crate::MDBResponse;
+use crate::MDBStatus;
+use crate::Mdb;
+
+use defmt::Format;
+use embedded_hal::delay::DelayNs;
+use enumn::N;
+
+//Coin hoppers / tube dispensers pay change in machines without a tube changer (or
+//alongside one).  There can be two on the bus, and their commands are offsets from
+//their address, in the same way as the coin changer's.
+
+pub const HOPPER_1_ADDRESS: u8 = 0x58;
+pub const HOPPER_2_ADDRESS: u8 = 0x70;
+
+const RESET_CMD: u8 = 0x00;
+const SETUP_CMD: u8 = 0x01;
+const STATUS_CMD: u8 = 0x02;
+const POLL_CMD: u8 = 0x03;
+const MANUAL_DISPENSE_ENABLE_CMD: u8 = 0x04;
+const DISPENSE_CMD: u8 = 0x05;
+const DISPENSE_VALUE_CMD: u8 = 0x06;
+
+//Expansion commands all start with address + 0x07
+const EXPANSION_PREFIX: u8 = 0x07;
+const EXPANSION_IDENT_CMD: u8 = 0x00;
+const EXPANSION_PAYOUT_STATUS_CMD: u8 = 0x03;
+const EXPANSION_PAYOUT_VALUE_POLL_CMD: u8 = 0x04;
+
+const PAYOUT_POLL_INTERVAL_MS: u32 = 50;
+
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum HopperNumber {
+    Hopper1,
+    Hopper2,
+}
+
+impl HopperNumber {
+    pub fn address(&self) -> u8 {
+        match self {
+            HopperNumber::Hopper1 => HOPPER_1_ADDRESS,
+            HopperNumber::Hopper2 => HOPPER_2_ADDRESS,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct HopperCoinType {
+    pub value: Money,
+    //Coins the hopper thinks it holds
+    pub count: u16,
+    //The hopper's low level sensor for this coin is uncovered
+    pub low: bool,
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct HopperIdentification {
+    pub manufacturer_code: [u8; 3],
+    pub serial_number: [u8; 12],
+    pub model: [u8; 12],
+    pub software_version: [u8; 2],
+    pub optional_features: u32,
+}
+
+#[derive(Copy, Clone, Format, N)]
+pub enum HopperStatus {
+    PayoutBusy = 0x02,
+    DefectiveSensor = 0x04,
+    HopperJam = 0x07,
+    RomChecksumError = 0x08,
+    HopperBusy = 0x10,
+    HopperWasReset = 0x11,
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct HopperDispenseEvent {
+    pub coin_type: u8,
+    //Of each coin - zero if the coin type isn't one we know
+    pub value: Money,
+    pub number: u8,
+}
+
+/// Reply to payout_value_poll
+#[derive(Copy, Clone, Format)]
+pub enum HopperPayoutPoll {
+    //Value paid out so far
+    Paying(Money),
+    //The hopper ACKed - the payout is over
+    Finished,
+    //No reply, a bad checksum or a NAK - it says nothing about the payout, so ask again
+    NoReply,
+}
+
+#[derive(Copy, Clone, Format)]
+pub enum HopperPollEvent {
+    Status(HopperStatus),
+    //Coins paid out with the hopper's own buttons
+    ManualDispense(HopperDispenseEvent),
+    //The hopper reset unexpectedly, and we have re-initialised it (true if successful)
+    Reinitialised(bool),
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct CoinHopper {
+    pub address: u8,
+    pub feature_level: u8,
+    pub country_code: [u8; 2],
+    pub scaling_factor: u8,
+    pub decimal_places: u8,
+    pub coin_types: [Option<HopperCoinType>; 16],
+    pub identification: Option<HopperIdentification>,
+    //How long a payout may take before we give up on it
+    pub payout_timeout_ms: u32,
+}
+
+impl CoinHopper {
+    /// currency is the one the machine works in - a hopper set up for another is refused
+    pub fn init<T: embedded_io::Write + embedded_io::Read>(
+        bus: &mut Mdb<T>,
+        number: HopperNumber,
+        currency: Currency,
+    ) -> Option<Self> {
+        let address = number.address();
+        bus.send_data(&[address | RESET_CMD]);
+        bus.timer.delay_ms(100);
+
+        //The first poll after a reset gets the 'just reset' status - consume it
+        let mut buf: [u8; 16] = [0x00; 16];
+        bus.send_data(&[address | POLL_CMD]);
+        let _ = bus.receive_response(&mut buf);
+
+        let mut hopper = Self::setup(bus, address)?;
+        currency.check(hopper.currency()).ok()?;
+        hopper.update_status(bus);
+        hopper.identification = hopper.identify(bus);
+        Some(hopper)
+    }
+
+    fn setup<T: embedded_io::Write + embedded_io::Read>(
+        bus: &mut Mdb<T>,
+        address: u8,
+    ) -> Option<Self> {
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[address | SETUP_CMD]);
+        let count = match bus.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count >= 5 => count,
+            _ => {
+                defmt::debug!("No setup reply from hopper at {=u8:#04x}", address);
+                return None;
+            }
+        };
+
+        //Level, currency, scaling factor, decimal places, then the value of each coin type
+        let format = WireFormat::new(
+            Currency::from_mdb_code(u16::from_be_bytes([buf[1], buf[2]])),
+            buf[3],
+            buf[4],
+        );
+        let mut coin_types: [Option<HopperCoinType>; 16] = [None; 16];
+        for (i, byte) in buf[5..count.min(21)].iter().enumerate() {
+            if *byte != 0x00 {
+                coin_types[i] = Some(HopperCoinType {
+                    value: Money::from_scaled(*byte as u32, format),
+                    count: 0,
+                    low: false,
+                });
+            }
+        }
+        let hopper = Self {
+            address,
+            feature_level: buf[0],
+            country_code: [buf[1], buf[2]],
+            scaling_factor: buf[3],
+            decimal_places: buf[4],
+            coin_types,
+            identification: None,
+            payout_timeout_ms: 30000,
+        };
+        let currency = hopper.currency();
+        defmt::debug!("Hopper {=u8:#04x} currency {}", address, currency);
+        currency::check_decimal_places(currency, hopper.decimal_places);
+        Some(hopper)
+    }
+
+    fn reinitialise<T: embedded_io::Write + embedded_io::Read>(&mut self, bus: &mut Mdb<T>) -> bool {
+        defmt::info!("Hopper {=u8:#04x} reset unexpectedly - re-initialising", self.address);
+        match Self::setup(bus, self.address) {
+            Some(mut hopper) => {
+                //Its' coins are no use to us in a different currency
+                if self.currency().check(hopper.currency()).is_err() {
+                    return false;
+                }
+                //Keep the counts we had until update_status gives us new ones
+                for (new, old) in hopper.coin_types.iter_mut().zip(self.coin_types.iter()) {
+                    if let (Some(new), Some(old)) = (new.as_mut(), old) {
+                        if new.value == old.value {
+                            new.count = old.count;
+                            new.low = old.low;
+                        }
+                    }
+                }
+                let payout_timeout_ms = self.payout_timeout_ms;
+                let identification = self.identification;
+                *self = hopper;
+                self.payout_timeout_ms = payout_timeout_ms;
+                self.identification = identification;
+                self.update_status(bus)
+            }
+            None => {
+                defmt::error!("Hopper failed to re-initialise");
+                false
+            }
+        }
+    }
+
+    /// Ask the hopper how many of each coin it holds, and which are running low
+    pub fn update_status<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> bool {
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[self.address | STATUS_CMD]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count >= 2 => {
+                let low_mask = (buf[0] as u16) << 8 | buf[1] as u16;
+                for (i, counts) in buf[2..count].chunks_exact(2).take(16).enumerate() {
+                    if let Some(coin) = self.coin_types[i].as_mut() {
+                        coin.count = (counts[0] as u16) << 8 | counts[1] as u16;
+                        coin.low = low_mask & (0x01 << i) != 0;
+                    }
+                }
+                true
+            }
+            _ => {
+                defmt::debug!("No status reply from hopper");
+                false
+            }
+        }
+    }
+
+    /// Enable the hopper's manual dispense buttons for these coin types
+    pub fn enable_manual_dispense<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        coin_mask: u16,
+    ) -> bool {
+        bus.send_data_and_confirm_ack(&[
+            self.address | MANUAL_DISPENSE_ENABLE_CMD,
+            (coin_mask >> 8) as u8,
+            (coin_mask & 0xFF) as u8,
+        ])
+    }
+
+    pub fn poll<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> [Option<HopperPollEvent>; 16] {
+        let mut poll_results: [Option<HopperPollEvent>; 16] = [None; 16];
+        let mut result_count: usize = 0;
+
+        let mut buf: [u8; 16] = [0x00; 16];
+        bus.send_data(&[self.address | POLL_CMD]);
+        if let MDBResponse::Data(count) = bus.receive_response(&mut buf) {
+            let mut index = 0;
+            while index < count && result_count < poll_results.len() {
+                let byte = buf[index];
+                if byte & 0x80 == 0x80 {
+                    //Manual dispense - 1yyyxxxx, yyy = number of coins, xxxx = coin type,
+                    //followed by the number left (which we fetch properly from the status)
+                    let coin_type = byte & 0x0F;
+                    poll_results[result_count] =
+                        Some(HopperPollEvent::ManualDispense(HopperDispenseEvent {
+                            coin_type,
+                            value: self.coin_types[coin_type as usize]
+                                .map(|c| c.value)
+                                .unwrap_or(Money::zero(self.currency())),
+                            number: (byte >> 4) & 0x07,
+                        }));
+                    result_count += 1;
+                    index += 2;
+                    continue;
+                }
+                match HopperStatus::n(byte) {
+                    Some(status) => {
+                        poll_results[result_count] = Some(HopperPollEvent::Status(status));
+                        result_count += 1;
+                    }
+                    None => defmt::debug!("Unrecognised status byte from hopper {=u8:#04x}", byte),
+                }
+                index += 1;
+            }
+        }
+
+        let mut dispensed = false;
+        for result in poll_results.iter_mut() {
+            match result {
+                Some(HopperPollEvent::Status(HopperStatus::HopperWasReset)) => {
+                    *result = Some(HopperPollEvent::Reinitialised(self.reinitialise(bus)));
+                }
+                Some(HopperPollEvent::ManualDispense(_)) => dispensed = true,
+                _ => {}
+            }
+        }
+        if dispensed {
+            self.update_status(bus);
+        }
+        poll_results
+    }
+
+    /// Dispense a number of coins of one type.  Returns once the hopper has accepted
+    /// the command - follow progress with payout_value_poll.
+    pub fn dispense<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        coin_type: u8,
+        number: u16,
+    ) -> bool {
+        if self.coin_types.get(coin_type as usize).is_none_or(|c| c.is_none()) {
+            defmt::error!("Hopper has no coin type {}", coin_type);
+            return false;
+        }
+        let number = number.to_be_bytes();
+        bus.send_data_and_confirm_ack(&[
+            self.address | DISPENSE_CMD,
+            coin_type,
+            number[0],
+            number[1],
+        ])
+    }
+
+    /// Dispense coins to a value - the hopper chooses the coins.  Returns once the
+    /// hopper has accepted the command - follow progress with payout_value_poll.
+    pub fn dispense_value<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        amount: Money,
+    ) -> bool {
+        let scaled = match self.to_wire(amount) {
+            Some(scaled) => scaled.to_be_bytes(),
+            None => {
+                defmt::error!("{} cannot be dispensed by the hopper", amount);
+                return false;
+            }
+        };
+        bus.send_data_and_confirm_ack(&[self.address | DISPENSE_VALUE_CMD, scaled[0], scaled[1]])
+    }
+
+    /// Value paid out so far - the hopper just ACKs once it has finished
+    pub fn payout_value_poll<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+    ) -> HopperPayoutPoll {
+        let mut buf: [u8; 16] = [0x00; 16];
+        bus.send_data(&[self.address | EXPANSION_PREFIX, EXPANSION_PAYOUT_VALUE_POLL_CMD]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count >= 2 => {
+                HopperPayoutPoll::Paying(self.from_wire((buf[0] as u16) << 8 | buf[1] as u16))
+            }
+            MDBResponse::StatusMsg(MDBStatus::ACK) => HopperPayoutPoll::Finished,
+            _ => HopperPayoutPoll::NoReply,
+        }
+    }
+
+    /// Number of each coin type paid out by the last dispense
+    pub fn payout_status<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+    ) -> Option<[u16; 16]> {
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[self.address | EXPANSION_PREFIX, EXPANSION_PAYOUT_STATUS_CMD]);
+        //Two bytes for each coin type, up to the last one the hopper has
+        let expected = 2 * self
+            .coin_types
+            .iter()
+            .rposition(|c| c.is_some())
+            .map_or(1, |last| last + 1);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count < expected => {
+                defmt::debug!("Short payout status from hopper: {} bytes", count);
+                None
+            }
+            MDBResponse::Data(count) => {
+                let mut coins: [u16; 16] = [0; 16];
+                for (i, counts) in buf[0..count].chunks_exact(2).take(16).enumerate() {
+                    coins[i] = (counts[0] as u16) << 8 | counts[1] as u16;
+                }
+                Some(coins)
+            }
+            MDBResponse::StatusMsg(_) => {
+                //An ACK here means the hopper is still busy paying out
+                defmt::debug!("Hopper did not report payout status");
+                None
+            }
+        }
+    }
+
+    /// Pay out an amount, waiting for the hopper to finish.  Returns the amount paid.
+    pub fn payout<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        amount: Money,
+    ) -> Money {
+        let mut paid = Money::zero(self.currency());
+        //The hopper can only pay whole scaling units
+        let payable = match amount.to_scaled_floor(self.wire_format()) {
+            Some(scaled) => Money::from_scaled(scaled, self.wire_format()),
+            None => return paid,
+        };
+        if payable.is_zero() || !self.dispense_value(bus, payable) {
+            return paid;
+        }
+
+        let started_at = bus.timer.get_counter().ticks();
+        loop {
+            bus.timer.delay_ms(PAYOUT_POLL_INTERVAL_MS);
+            match self.payout_value_poll(bus) {
+                HopperPayoutPoll::Paying(so_far) => paid = so_far,
+                HopperPayoutPoll::Finished => break,
+                HopperPayoutPoll::NoReply => {}
+            }
+            let elapsed_us = bus.timer.get_counter().ticks().saturating_sub(started_at);
+            if elapsed_us >= self.payout_timeout_ms as u64 * 1000 {
+                defmt::error!("Hopper payout timed out");
+                break;
+            }
+        }
+
+        //Even after a timeout - if the hopper has stopped, it can tell us what it paid
+        if let Some(coins) = self.payout_status(bus) {
+            let mut total: u32 = 0;
+            for (i, number) in coins.iter().enumerate() {
+                if let Some(coin) = self.coin_types[i] {
+                    total =
+                        total.saturating_add(coin.value.minor_units.saturating_mul(*number as u32));
+                }
+            }
+            paid = Money::new(total, self.currency());
+        }
+        self.update_status(bus);
+        paid
+    }
+
+    pub fn identify<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+    ) -> Option<HopperIdentification> {
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[self.address | EXPANSION_PREFIX, EXPANSION_IDENT_CMD]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count >= 33 => Some(HopperIdentification {
+                manufacturer_code: buf[0..3].try_into().unwrap(),
+                serial_number: buf[3..15].try_into().unwrap(),
+                model: buf[15..27].try_into().unwrap(),
+                software_version: buf[27..29].try_into().unwrap(),
+                optional_features: u32::from_be_bytes(buf[29..33].try_into().unwrap()),
+            }),
+            MDBResponse::StatusMsg(MDBStatus::ACK) | MDBResponse::Data(_) => {
+                defmt::debug!("Hopper did not identify itself");
+                None
+            }
+            _ => None,
+        }
+    }
+
+    pub fn currency(&self) -> Currency {
+        Currency::from_mdb_code(u16::from_be_bytes(self.country_code))
+    }
+
+    pub fn wire_format(&self) -> WireFormat {
+        WireFormat::new(self.currency(), self.scaling_factor, self.decimal_places)
+    }
+
+    /// Convert an amount to the hopper's scaled format.  None if it is in the wrong
+    /// currency, isn't a whole number of scaling units, or is too big.
+    pub fn to_wire(&self, amount: Money) -> Option<u16> {
+        u16::try_from(amount.to_scaled(self.wire_format())?).ok()
+    }
+
+    /// Convert a scaled amount from the hopper
+    pub fn from_wire(&self, scaled: u16) -> Money {
+        Money::from_scaled(scaled as u32, self.wire_format())
+    }
+
+    /// Value of the coins the hopper holds
+    pub fn available(&self) -> Money {
+        let total = self.coin_types.iter().flatten().fold(0, |total: u32, c| {
+            total.saturating_add(c.value.minor_units.saturating_mul(c.count as u32))
+        });
+        Money::new(total, self.currency())
+    }
+}
+
+/// Pay change from the coin changer (if there is one) first, then from each hopper
+/// in turn for whatever is left.  Returns the total paid.
+pub fn payout_with_hoppers<T: embedded_io::Write + embedded_io::Read>(
+    acceptor: Option<&mut CoinAcceptor>,
+    hoppers: &mut [CoinHopper],
+    bus: &mut Mdb<T>,
+    amount: Money,
+) -> Money {
+    let mut paid = Money::zero(amount.currency);
+    if let Some(acceptor) = acceptor {
+        paid = paid.saturating_add(acceptor.payout(bus, amount)).unwrap_or(paid);
+    }
+    for hopper in hoppers.iter_mut() {
+        let remaining = match amount.saturating_sub(paid) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => break,
+        };
+        if hopper.currency() != amount.currency {
+            defmt::error!("Hopper {=u8:#04x} is in a different currency", hopper.address);
+            continue;
+        }
+        paid = paid.saturating_add(hopper.payout(bus, remaining)).unwrap_or(paid);
+    }
+    if paid != amount {
+        defmt::info!("Change of {} requested, {} paid", amount, paid);
+    }
+    paid
+}
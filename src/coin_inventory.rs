@@ -0,0 +1,201 @@
+use crate::coin_acceptor::{CoinAcceptor, CoinRouting, PollEvent};
+use crate::money::Money;
+
+use defmt::Format;
+
+//Keeps track of where every coin has gone - into the tubes, the cashbox, or back out
+//again - so that what the changer reports can be checked against what we expect,
+//and the money in the machine can be reconciled at each service visit.
+
+/// The changer reported a different tube count to the one we expected
+#[derive(Copy, Clone, Format)]
+pub struct TubeDiscrepancy {
+    pub coin_type: u8,
+    pub expected: u8,
+    pub reported: u8,
+}
+
+/// Money in the machine, as at the time of the audit
+#[derive(Copy, Clone, Format)]
+pub struct CashAudit {
+    pub tube_value: Money,
+    pub cashbox_value: Money,
+    pub paid_out_value: Money,
+    pub manually_dispensed_value: Money,
+    pub filled_value: Money,
+    pub discrepancy_count: u16,
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct CoinInventory {
+    //All indexed by coin type, the same as CoinAcceptor::coin_types
+    //What we think is in each tube
+    pub tube_counts: [u8; 16],
+    //Counts since the last service visit
+    pub cashbox_counts: [u16; 16],
+    pub paid_out_counts: [u16; 16],
+    pub manually_dispensed_counts: [u16; 16],
+    pub filled_counts: [u16; 16],
+    //Coin types whose tube count has disagreed with the changer since the last service visit
+    pub discrepancy_mask: u16,
+    pub discrepancy_count: u16,
+}
+
+impl CoinInventory {
+    /// Start tracking from the changer's current tube counts
+    pub fn new(acceptor: &CoinAcceptor) -> Self {
+        let mut inventory = Self {
+            tube_counts: [0; 16],
+            cashbox_counts: [0; 16],
+            paid_out_counts: [0; 16],
+            manually_dispensed_counts: [0; 16],
+            filled_counts: [0; 16],
+            discrepancy_mask: 0x0000,
+            discrepancy_count: 0,
+        };
+        for (i, c) in acceptor.coin_types.iter().enumerate() {
+            if let Some(coin) = c {
+                inventory.tube_counts[i] = coin.num_coins;
+            }
+        }
+        inventory
+    }
+
+    /// Account for a coin acceptor poll event.  Coins into the tubes and manual dispenses
+    /// come with the changer's new tube count, which is checked against ours.
+    pub fn record_event(&mut self, event: &PollEvent) -> Option<TubeDiscrepancy> {
+        match event {
+            PollEvent::Coin(coin) => match coin.routing {
+                CoinRouting::Tube => {
+                    let expected = self.tube_counts[coin.coin_type as usize].saturating_add(1);
+                    self.check(coin.coin_type, expected, coin.coins_remaining)
+                }
+                CoinRouting::CashBox => {
+                    let count = &mut self.cashbox_counts[coin.coin_type as usize];
+                    *count = count.saturating_add(1);
+                    None
+                }
+                _ => None,
+            },
+            PollEvent::ManualDispense(dispense) => {
+                let manual = &mut self.manually_dispensed_counts[dispense.coin_type as usize];
+                *manual = manual.saturating_add(dispense.number as u16);
+                let expected =
+                    self.tube_counts[dispense.coin_type as usize].saturating_sub(dispense.number);
+                self.check(dispense.coin_type, expected, dispense.coins_remaining)
+            }
+            _ => None,
+        }
+    }
+
+    /// Account for a payout.  Call after CoinAcceptor::payout, which refreshes the tube
+    /// counts - the coins missing from the tubes should add up to the amount paid.
+    /// Returns false (and flags the coin types) if they don't.
+    pub fn record_payout(&mut self, acceptor: &CoinAcceptor, amount_paid: Money) -> bool {
+        let mut value_removed: u32 = 0;
+        let mut changed_mask: u16 = 0x0000;
+        for (i, c) in acceptor.coin_types.iter().enumerate() {
+            if let Some(coin) = c {
+                if coin.num_coins < self.tube_counts[i] {
+                    let removed = self.tube_counts[i] - coin.num_coins;
+                    value_removed += coin.value.minor_units * removed as u32;
+                    self.paid_out_counts[i] = self.paid_out_counts[i].saturating_add(removed as u16);
+                    changed_mask |= 0x01 << i;
+                }
+                self.tube_counts[i] = coin.num_coins;
+            }
+        }
+        if value_removed != amount_paid.minor_units {
+            defmt::error!(
+                "Payout of {} removed {} worth of coins from the tubes",
+                amount_paid,
+                value_removed
+            );
+            self.discrepancy_mask |= changed_mask;
+            self.discrepancy_count = self.discrepancy_count.saturating_add(1);
+            return false;
+        }
+        true
+    }
+
+    /// Account for coins put into the tubes by hand, as given by
+    /// CoinAcceptor::controlled_manual_fill_report
+    pub fn record_fill(&mut self, report: &[u8; 16]) {
+        for (i, count) in report.iter().enumerate() {
+            self.tube_counts[i] = self.tube_counts[i].saturating_add(*count);
+            self.filled_counts[i] = self.filled_counts[i].saturating_add(*count as u16);
+        }
+    }
+
+    /// Compare our tube counts with the changer's (eg after CoinAcceptor::update_coin_counts),
+    /// then adopt the changer's counts.
+    pub fn reconcile_tubes(&mut self, acceptor: &CoinAcceptor) -> [Option<TubeDiscrepancy>; 16] {
+        let mut discrepancies: [Option<TubeDiscrepancy>; 16] = [None; 16];
+        for (i, c) in acceptor.coin_types.iter().enumerate() {
+            if let Some(coin) = c {
+                discrepancies[i] = self.check(i as u8, self.tube_counts[i], coin.num_coins);
+            }
+        }
+        discrepancies
+    }
+
+    /// Money in the tubes versus money in the cashbox, for reconciling at a service visit
+    pub fn audit(&self, acceptor: &CoinAcceptor) -> CashAudit {
+        let zero = Money::zero(acceptor.currency());
+        let mut audit = CashAudit {
+            tube_value: zero,
+            cashbox_value: zero,
+            paid_out_value: zero,
+            manually_dispensed_value: zero,
+            filled_value: zero,
+            discrepancy_count: self.discrepancy_count,
+        };
+        for (i, c) in acceptor.coin_types.iter().enumerate() {
+            if let Some(coin) = c {
+                let totals = [
+                    (&mut audit.tube_value, self.tube_counts[i] as u32),
+                    (&mut audit.cashbox_value, self.cashbox_counts[i] as u32),
+                    (&mut audit.paid_out_value, self.paid_out_counts[i] as u32),
+                    (&mut audit.manually_dispensed_value, self.manually_dispensed_counts[i] as u32),
+                    (&mut audit.filled_value, self.filled_counts[i] as u32),
+                ];
+                for (total, count) in totals {
+                    *total = total.saturating_add(coin.value.saturating_mul(count)).unwrap_or(*total);
+                }
+            }
+        }
+        audit
+    }
+
+    /// The cashbox has been emptied and the audit taken - start counting again.
+    /// Tube counts carry on, as the coins are still in the machine.
+    pub fn service_visit(&mut self) {
+        self.cashbox_counts = [0; 16];
+        self.paid_out_counts = [0; 16];
+        self.manually_dispensed_counts = [0; 16];
+        self.filled_counts = [0; 16];
+        self.discrepancy_mask = 0x0000;
+        self.discrepancy_count = 0;
+    }
+
+    //Flag a discrepancy if the changer doesn't agree with us, and go with what it says
+    fn check(&mut self, coin_type: u8, expected: u8, reported: u8) -> Option<TubeDiscrepancy> {
+        self.tube_counts[coin_type as usize] = reported;
+        if expected == reported {
+            return None;
+        }
+        defmt::info!(
+            "Tube {} count discrepancy - expected {}, changer reports {}",
+            coin_type,
+            expected,
+            reported
+        );
+        self.discrepancy_mask |= 0x01 << coin_type;
+        self.discrepancy_count = self.discrepancy_count.saturating_add(1);
+        Some(TubeDiscrepancy {
+            coin_type,
+            expected,
+            reported,
+        })
+    }
+}
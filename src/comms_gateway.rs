@@ -0,0 +1,523 @@
+use crate::coin_acceptor::{CoinRouting, PollEvent};
+use crate::credit::PaymentMethod;
+use crate::currency::Currency;
+use crate::money::{Money, WireFormat};
+use crate::MDBResponse;
+use crate::Mdb;
+
+use defmt::Format;
+use embedded_hal::delay::DelayNs;
+
+//Communications gateway - a telemetry modem on the bus, which passes transactions and
+//DTS events on to the back office, and can tell us the time.
+//
+//Reports are sent as they happen (report_vend, report_coin_event etc).  If the gateway is
+//busy and doesn't ACK one, it is queued and sent again on the next poll.
+
+pub const COMMS_GATEWAY_ADDRESS: u8 = 0x18;
+
+const RESET_CMD: u8 = 0x18;
+const SETUP_CMD: u8 = 0x19;
+const POLL_CMD: u8 = 0x1A;
+const REPORT_CMD: u8 = 0x1B;
+const CONTROL_CMD: u8 = 0x1C;
+const EXPANSION_CMD: u8 = 0x1F;
+
+const SETUP_CONFIG_DATA: u8 = 0x00;
+
+const REPORT_TRANSACTION: u8 = 0x01;
+const REPORT_DTS_EVENT: u8 = 0x02;
+
+const CONTROL_DISABLE: u8 = 0x00;
+const CONTROL_ENABLE: u8 = 0x01;
+
+const EXPANSION_REQUEST_ID: u8 = 0x00;
+const EXPANSION_TIME_DATE_REQUEST: u8 = 0x02;
+
+const POLL_REPLY_JUST_RESET: u8 = 0x00;
+const POLL_REPLY_CONFIG_DATA: u8 = 0x01;
+const POLL_REPLY_TIME_DATE: u8 = 0x05;
+const POLL_REPLY_ERROR: u8 = 0x06;
+const POLL_REPLY_DATA_TRANSMITTED: u8 = 0x07;
+const POLL_REPLY_PERIPHERAL_ID: u8 = 0x09;
+
+//Feature level we tell the gateway we are
+const VMC_FEATURE_LEVEL: u8 = 0x01;
+
+//Reports waiting to be sent again, and the longest one
+const REPORT_QUEUE_LENGTH: usize = 8;
+const MAX_REPORT_LENGTH: usize = 24;
+
+//EVA-DTS event codes are 10 characters, space padded
+pub const DTS_EVENT_CODE_LENGTH: usize = 10;
+
+#[derive(Copy, Clone, Format)]
+pub enum TransactionType {
+    PaidVend(PaymentMethod),
+    FailedVend,
+    //Coin (or bill) accepted
+    CashIn,
+    //Change paid out
+    CashOut,
+    //Coins paid out with the changer's own buttons
+    ManualDispense,
+    //Coins put into the tubes by hand
+    Fill,
+}
+
+impl TransactionType {
+    fn code(&self) -> u8 {
+        match self {
+            TransactionType::PaidVend(PaymentMethod::Cash) => 0x01,
+            TransactionType::PaidVend(PaymentMethod::Cashless) => 0x02,
+            TransactionType::FailedVend => 0x03,
+            TransactionType::CashIn => 0x04,
+            TransactionType::CashOut => 0x05,
+            TransactionType::ManualDispense => 0x06,
+            TransactionType::Fill => 0x07,
+        }
+    }
+}
+
+/// Date and time, as given by the gateway
+#[derive(Copy, Clone, Format, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    //BCD - year (2 bytes), month, day, hour, minute, second
+    fn from_bcd(bcd: &[u8]) -> Option<Self> {
+        if bcd.len() < 7 {
+            return None;
+        }
+        let mut digits: [u8; 7] = [0; 7];
+        for (i, byte) in bcd[0..7].iter().enumerate() {
+            if byte >> 4 > 9 || byte & 0x0F > 9 {
+                return None;
+            }
+            digits[i] = (byte >> 4) * 10 + (byte & 0x0F);
+        }
+        Some(Self {
+            year: digits[0] as u16 * 100 + digits[1] as u16,
+            month: digits[2],
+            day: digits[3],
+            hour: digits[4],
+            minute: digits[5],
+            second: digits[6],
+        })
+    }
+
+    fn to_bcd(self) -> [u8; 7] {
+        let bcd = |v: u8| (v / 10) << 4 | (v % 10);
+        [
+            bcd((self.year / 100) as u8),
+            bcd((self.year % 100) as u8),
+            bcd(self.month),
+            bcd(self.day),
+            bcd(self.hour),
+            bcd(self.minute),
+            bcd(self.second),
+        ]
+    }
+}
+
+#[derive(Copy, Clone, Format)]
+pub enum GatewayPollEvent {
+    JustReset,
+    //The gateway reset unexpectedly, and we have re-initialised it (true if successful).
+    //Poll returns this in place of JustReset.
+    Reinitialised(bool),
+    TimeDate(DateTime),
+    Error(u8),
+    //Everything reported so far has reached the back office
+    DataTransmitted,
+    Unhandled(u8),
+}
+
+#[derive(Copy, Clone, Format)]
+struct QueuedReport {
+    data: [u8; MAX_REPORT_LENGTH],
+    len: usize,
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct CommsGateway {
+    pub feature_level: u8,
+    pub max_response_time: u16, //Seconds
+    pub manufacturer_code: [u8; 3],
+    pub serial_number: [u8; 12],
+    pub model_number: [u8; 12],
+    pub software_version: [u8; 2],
+    //We choose how amounts are scaled when reporting to the gateway
+    pub scale_factor: u8,
+    pub decimal_places: u8,
+    pub currency: Currency,
+    pub enabled: bool,
+    queue: [Option<QueuedReport>; REPORT_QUEUE_LENGTH],
+}
+
+impl CommsGateway {
+    /// Amounts will be reported in currency, scaled by scale_factor
+    pub fn init<T: embedded_io::Write + embedded_io::Read>(
+        bus: &mut Mdb<T>,
+        currency: Currency,
+        scale_factor: u8,
+        decimal_places: u8,
+    ) -> Option<Self> {
+        bus.send_data(&[RESET_CMD]);
+        bus.timer.delay_ms(100);
+
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[POLL_CMD]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(_) if buf[0] == POLL_REPLY_JUST_RESET => {}
+            _ => {
+                defmt::debug!("No reset reply from comms gateway");
+                return None;
+            }
+        }
+
+        let mut gateway = Self::setup(bus, currency, scale_factor, decimal_places)?;
+        gateway.identify(bus);
+        gateway.set_enabled(bus, true);
+        Some(gateway)
+    }
+
+    fn setup<T: embedded_io::Write + embedded_io::Read>(
+        bus: &mut Mdb<T>,
+        currency: Currency,
+        scale_factor: u8,
+        decimal_places: u8,
+    ) -> Option<Self> {
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[
+            SETUP_CMD,
+            SETUP_CONFIG_DATA,
+            VMC_FEATURE_LEVEL,
+            scale_factor,
+            decimal_places,
+        ]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count >= 4 && buf[0] == POLL_REPLY_CONFIG_DATA => {
+                Some(Self {
+                    feature_level: buf[1],
+                    max_response_time: (buf[2] as u16) << 8 | buf[3] as u16,
+                    manufacturer_code: [0x00; 3],
+                    serial_number: [0x00; 12],
+                    model_number: [0x00; 12],
+                    software_version: [0x00; 2],
+                    scale_factor,
+                    decimal_places,
+                    currency,
+                    enabled: false,
+                    queue: [None; REPORT_QUEUE_LENGTH],
+                })
+            }
+            _ => {
+                defmt::debug!("Invalid setup reply from comms gateway");
+                None
+            }
+        }
+    }
+
+    fn reinitialise<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> bool {
+        defmt::info!("Comms gateway reset unexpectedly - re-initialising");
+        match Self::setup(bus, self.currency, self.scale_factor, self.decimal_places) {
+            Some(mut gateway) => {
+                gateway.manufacturer_code = self.manufacturer_code;
+                gateway.serial_number = self.serial_number;
+                gateway.model_number = self.model_number;
+                gateway.software_version = self.software_version;
+                //Anything still queued hasn't been sent yet
+                gateway.queue = self.queue;
+                let enabled = self.enabled;
+                *self = gateway;
+                self.set_enabled(bus, enabled)
+            }
+            None => {
+                defmt::error!("Comms gateway failed to re-initialise");
+                false
+            }
+        }
+    }
+
+    fn identify<T: embedded_io::Write + embedded_io::Read>(&mut self, bus: &mut Mdb<T>) -> bool {
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[EXPANSION_CMD, EXPANSION_REQUEST_ID]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count >= 30 && buf[0] == POLL_REPLY_PERIPHERAL_ID => {
+                self.manufacturer_code.copy_from_slice(&buf[1..4]);
+                self.serial_number.copy_from_slice(&buf[4..16]);
+                self.model_number.copy_from_slice(&buf[16..28]);
+                self.software_version.copy_from_slice(&buf[28..30]);
+                true
+            }
+            _ => {
+                defmt::debug!("Comms gateway did not identify itself");
+                false
+            }
+        }
+    }
+
+    pub fn set_enabled<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        enable: bool,
+    ) -> bool {
+        self.enabled = enable;
+        let sub = if enable {
+            CONTROL_ENABLE
+        } else {
+            CONTROL_DISABLE
+        };
+        bus.send_data_and_confirm_ack(&[CONTROL_CMD, sub])
+    }
+
+    /// Poll the gateway, and send any reports that are waiting
+    pub fn poll<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> [Option<GatewayPollEvent>; 4] {
+        let mut poll_results: [Option<GatewayPollEvent>; 4] = [None; 4];
+        let mut result_count: usize = 0;
+
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[POLL_CMD]);
+        if let MDBResponse::Data(count) = bus.receive_response(&mut buf) {
+            let mut index = 0;
+            while index < count && result_count < poll_results.len() {
+                let (event, len) = match buf[index] {
+                    POLL_REPLY_JUST_RESET => (GatewayPollEvent::JustReset, 1),
+                    POLL_REPLY_TIME_DATE => match DateTime::from_bcd(&buf[index + 1..count]) {
+                        Some(time) => (GatewayPollEvent::TimeDate(time), 8),
+                        None => (
+                            GatewayPollEvent::Unhandled(POLL_REPLY_TIME_DATE),
+                            count - index,
+                        ),
+                    },
+                    POLL_REPLY_ERROR if index + 1 < count => {
+                        (GatewayPollEvent::Error(buf[index + 1]), 2)
+                    }
+                    POLL_REPLY_DATA_TRANSMITTED => (GatewayPollEvent::DataTransmitted, 1),
+                    other => {
+                        defmt::debug!(
+                            "Unhandled poll reply from comms gateway: {=[u8]:#04x}",
+                            buf[index..count]
+                        );
+                        (GatewayPollEvent::Unhandled(other), count - index)
+                    }
+                };
+                poll_results[result_count] = Some(event);
+                result_count += 1;
+                index += len;
+            }
+        }
+
+        for result in poll_results.iter_mut() {
+            if matches!(result, Some(GatewayPollEvent::JustReset)) {
+                *result = Some(GatewayPollEvent::Reinitialised(self.reinitialise(bus)));
+            }
+        }
+        self.send_queued(bus);
+        poll_results
+    }
+
+    /// Report a transaction - amount is scaled with our scale factor
+    pub fn report_transaction<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        transaction: TransactionType,
+        address: [u8; 2],
+        amount: Money,
+    ) -> bool {
+        let amount = match self.to_wire(amount) {
+            Some(scaled) => scaled.to_be_bytes(),
+            None => return false,
+        };
+        self.send_report(
+            bus,
+            &[
+                REPORT_CMD,
+                REPORT_TRANSACTION,
+                transaction.code(),
+                address[0],
+                address[1],
+                amount[0],
+                amount[1],
+            ],
+        )
+    }
+
+    /// Report an EVA-DTS event - code is the event identifier (eg b"EGS"), padded to 10
+    /// characters.  active is whether the condition has started or ended.
+    pub fn report_dts_event<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        code: &[u8],
+        time: DateTime,
+        active: bool,
+    ) -> bool {
+        let mut msg: [u8; 2 + DTS_EVENT_CODE_LENGTH + 7 + 1] = [0x20; 20];
+        msg[0] = REPORT_CMD;
+        msg[1] = REPORT_DTS_EVENT;
+        let code_len = code.len().min(DTS_EVENT_CODE_LENGTH);
+        msg[2..2 + code_len].copy_from_slice(&code[..code_len]);
+        msg[12..19].copy_from_slice(&time.to_bcd());
+        msg[19] = active as u8;
+        self.send_report(bus, &msg)
+    }
+
+    pub fn report_vend<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        method: PaymentMethod,
+        address: [u8; 2],
+        price: Money,
+    ) -> bool {
+        self.report_transaction(bus, TransactionType::PaidVend(method), address, price)
+    }
+
+    pub fn report_failed_vend<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        address: [u8; 2],
+        price: Money,
+    ) -> bool {
+        self.report_transaction(bus, TransactionType::FailedVend, address, price)
+    }
+
+    /// Forward a coin acceptor poll event - coins accepted and manual dispenses are reported
+    pub fn report_coin_event<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        event: &PollEvent,
+    ) -> bool {
+        match event {
+            PollEvent::Coin(coin) if !matches!(coin.routing, CoinRouting::Reject) => {
+                self.report_transaction(bus, TransactionType::CashIn, [0x00, 0x00], coin.value)
+            }
+            PollEvent::ManualDispense(dispense) => self.report_transaction(
+                bus,
+                TransactionType::ManualDispense,
+                [0x00, 0x00],
+                dispense.value.saturating_mul(dispense.number as u32),
+            ),
+            _ => true,
+        }
+    }
+
+    /// Change paid out, eg from CoinAcceptor::payout
+    pub fn report_payout<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        amount: Money,
+    ) -> bool {
+        self.report_transaction(bus, TransactionType::CashOut, [0x00, 0x00], amount)
+    }
+
+    /// Ask the gateway for the time, waiting up to max_response_time for it to reply
+    pub fn request_time<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> Option<DateTime> {
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[EXPANSION_CMD, EXPANSION_TIME_DATE_REQUEST]);
+        //It might reply straight away, or ACK and give us the time when next polled
+        if let MDBResponse::Data(count) = bus.receive_response(&mut buf) {
+            if buf[0] == POLL_REPLY_TIME_DATE {
+                return DateTime::from_bcd(&buf[1..count]);
+            }
+        }
+        for _ in 0..self.max_response_time.max(1) {
+            for event in self.poll(bus).into_iter().flatten() {
+                if let GatewayPollEvent::TimeDate(time) = event {
+                    return Some(time);
+                }
+            }
+            bus.timer.delay_ms(1000);
+        }
+        defmt::error!("Comms gateway did not send the time");
+        None
+    }
+
+    /// Reports waiting to be sent
+    pub fn queued_reports(&self) -> usize {
+        self.queue.iter().filter(|r| r.is_some()).count()
+    }
+
+    fn send_report<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        report: &[u8],
+    ) -> bool {
+        //Keep them in order - don't jump the queue
+        if self.queued_reports() == 0 && bus.send_data_and_confirm_ack(report) {
+            return true;
+        }
+        match self.queue.iter().position(|r| r.is_none()) {
+            Some(i) => {
+                let mut data = [0x00; MAX_REPORT_LENGTH];
+                data[..report.len()].copy_from_slice(report);
+                self.queue[i] = Some(QueuedReport {
+                    data,
+                    len: report.len(),
+                });
+                true
+            }
+            None => {
+                defmt::error!("Comms gateway report queue full - report lost");
+                false
+            }
+        }
+    }
+
+    fn send_queued<T: embedded_io::Write + embedded_io::Read>(&mut self, bus: &mut Mdb<T>) {
+        for i in 0..REPORT_QUEUE_LENGTH {
+            if let Some(report) = self.queue[i] {
+                if !bus.send_data_and_confirm_ack(&report.data[..report.len]) {
+                    //Still busy - the rest wait behind this one
+                    break;
+                }
+                self.queue[i] = None;
+            }
+        }
+        //Shuffle what's left to the front (even if nothing was sent this time), so the
+        //next report goes in after it
+        let mut next = 0;
+        for i in 0..REPORT_QUEUE_LENGTH {
+            if self.queue[i].is_some() {
+                self.queue.swap(next, i);
+                next += 1;
+            }
+        }
+    }
+
+    pub fn wire_format(&self) -> WireFormat {
+        WireFormat::new(self.currency, self.scale_factor, self.decimal_places)
+    }
+
+    /// Convert an amount to the scaled format we report in.  None (and logged) if it is in
+    /// the wrong currency, or can't be sent exactly.
+    pub fn to_wire(&self, amount: Money) -> Option<u16> {
+        if amount.currency != self.currency {
+            defmt::error!("{} is not in the comms gateway's currency", amount);
+            return None;
+        }
+        match amount.to_scaled(self.wire_format()).map(u16::try_from) {
+            Some(Ok(scaled)) => Some(scaled),
+            _ => {
+                defmt::error!("{} cannot be reported to the comms gateway", amount);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,380 @@
+use crate::age_verification::{AgeRestrictions, AgeVerificationDevice};
+use crate::cashless_device::{CashlessDevice, CashlessSession, VendOutcome};
+use crate::coin_acceptor::{CoinAcceptor, PollEvent};
+use crate::coin_escrow::{CoinCredit, CoinCreditEvent, Refund};
+use crate::currency::Currency;
+use crate::money::Money;
+use crate::Mdb;
+
+use defmt::Format;
+
+//Brings together cash credit (coins, and anything else the application adds, eg bills)
+//and cashless session funds, so the application only has to deal with selections:
+//request_vend when a selection is made, vend_succeeded/vend_failed once the product
+//has (or hasn't) been delivered, then complete to give change and close the session.
+//Cash sales are reported to the card reader automatically.
+//
+//Nothing here waits on the card reader.  If the card has to pay, request_vend returns
+//AwaitingCashless, and the answer comes back from poll_cashless once the customer has
+//presented their card and the reader has made up its' mind.  A selection is paid for
+//entirely in cash or entirely by card - the two aren't combined.
+//
+//Either device may be absent, so they are passed in as Options.
+//
+//Selections in age_restrictions are only sold once the customer has passed age
+//verification, whichever way they pay.  The pass is cleared once that customer has no
+//cash credit or cashless session left, so it can't be used by the next one.
+
+#[derive(Copy, Clone, Format)]
+pub enum PaymentMethod {
+    Cash,
+    Cashless,
+}
+
+#[derive(Copy, Clone, Format)]
+pub enum VendAuthorisation {
+    Approved(PaymentMethod),
+    //Not enough cash, and no cashless payment either
+    InsufficientCredit,
+    //The card reader turned it down
+    Denied,
+    //Age-restricted, and the customer didn't pass verification (or there is no device)
+    AgeNotVerified,
+    //The card reader has been asked - poll_cashless gives the answer
+    AwaitingCashless,
+}
+
+//How long the customer has to present a card, and the reader to answer
+const CASHLESS_REQUEST_TIMEOUT_MS: u32 = 30000;
+
+#[derive(Copy, Clone, Format)]
+struct PendingVend {
+    method: PaymentMethod,
+    price: Money,
+    address: [u8; 2],
+}
+
+//A cashless vend waiting on the card reader
+#[derive(Copy, Clone, Format)]
+struct CashlessRequest {
+    vend: PendingVend,
+    //Sent to the reader - until then, we are waiting for a card to be presented
+    sent: bool,
+    started_at: u64, //64 bit timer counter, uS
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct CreditManager {
+    pub cash: CoinCredit,
+    pub session: Option<CashlessSession>,
+    pub age_restrictions: Option<AgeRestrictions>,
+    pending: Option<PendingVend>,
+    cashless_request: Option<CashlessRequest>,
+}
+
+impl CreditManager {
+    /// currency is the one the machine works in, eg from CoinAcceptor::currency
+    pub fn new(currency: Currency) -> Self {
+        Self {
+            cash: CoinCredit::new(currency),
+            session: None,
+            age_restrictions: None,
+            pending: None,
+            cashless_request: None,
+        }
+    }
+
+    /// Pass each coin acceptor poll event through here
+    pub fn handle_coin_event<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        acceptor: &mut CoinAcceptor,
+        bus: &mut Mdb<T>,
+        event: &PollEvent,
+    ) -> Option<CoinCreditEvent> {
+        self.cash.handle_event(acceptor, bus, event)
+    }
+
+    /// Cash credit from elsewhere (eg a bill validator).  False if it is in another currency.
+    pub fn add_cash(&mut self, amount: Money) -> bool {
+        match self.cash.credit.saturating_add(amount) {
+            Some(credit) => {
+                self.cash.credit = credit;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Poll the card reader - picks up a card tapped before a selection is made, and
+    /// notices if the reader ends the session.  Once a selection that returned
+    /// AwaitingCashless has been approved or turned down, returns the outcome.
+    pub fn poll_cashless<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        device: &mut CashlessDevice,
+        age_verifier: Option<&mut AgeVerificationDevice>,
+        bus: &mut Mdb<T>,
+    ) -> Option<VendAuthorisation> {
+        if let Some(request) = self.cashless_request {
+            return self.poll_cashless_request(device, age_verifier, bus, request);
+        }
+        match self.session.as_mut() {
+            Some(session) => {
+                if !device.session_poll(bus, session) {
+                    self.session = None;
+                    self.clear_age_pass_if_done(age_verifier);
+                }
+            }
+            None => {
+                self.session = device.poll_for_session(bus);
+            }
+        }
+        None
+    }
+
+    fn poll_cashless_request<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        device: &mut CashlessDevice,
+        age_verifier: Option<&mut AgeVerificationDevice>,
+        bus: &mut Mdb<T>,
+        request: CashlessRequest,
+    ) -> Option<VendAuthorisation> {
+        let elapsed_us = bus.timer.get_counter().ticks().saturating_sub(request.started_at);
+        let timed_out = elapsed_us >= CASHLESS_REQUEST_TIMEOUT_MS as u64 * 1000;
+
+        if !request.sent {
+            //Still waiting for the card
+            self.session = device.poll_for_session(bus);
+            let session = match self.session.as_ref() {
+                Some(session) => session,
+                None if timed_out => {
+                    defmt::debug!("No card presented");
+                    self.cashless_request = None;
+                    return Some(VendAuthorisation::InsufficientCredit);
+                }
+                None => return None,
+            };
+            let vend = request.vend;
+            if let Err(outcome) = device.send_vend_request(bus, session, vend.price, vend.address) {
+                self.cashless_request = None;
+                return Some(self.cashless_outcome(vend, outcome, age_verifier));
+            }
+            self.cashless_request = Some(CashlessRequest {
+                sent: true,
+                ..request
+            });
+            return None;
+        }
+
+        let outcome = match self.session.as_mut() {
+            Some(session) => device.vend_request_poll(bus, session),
+            None => Some(VendOutcome::SessionEnded),
+        };
+        let outcome = match outcome {
+            Some(outcome) => outcome,
+            None if timed_out => {
+                //Don't leave the reader with a vend we have given up on
+                device.cancel_transaction(bus);
+                VendOutcome::TimedOut
+            }
+            None => return None,
+        };
+        self.cashless_request = None;
+        Some(self.cashless_outcome(request.vend, outcome, age_verifier))
+    }
+
+    fn cashless_outcome(
+        &mut self,
+        vend: PendingVend,
+        outcome: VendOutcome,
+        age_verifier: Option<&mut AgeVerificationDevice>,
+    ) -> VendAuthorisation {
+        if self.session.is_some_and(|session| !session.active) {
+            self.session = None;
+            self.clear_age_pass_if_done(age_verifier);
+        }
+        match outcome {
+            VendOutcome::Approved(_) => {
+                self.pending = Some(vend);
+                VendAuthorisation::Approved(PaymentMethod::Cashless)
+            }
+            VendOutcome::TimedOut => VendAuthorisation::InsufficientCredit,
+            _ => VendAuthorisation::Denied,
+        }
+    }
+
+    /// The most a single selection can cost.  Cash and cashless funds can't be put
+    /// together for one vend, so this is whichever is more.  Cashless funds are only
+    /// counted if the reader has told us how much there is.
+    pub fn available(&self) -> Money {
+        match self.session.and_then(|session| session.funds_available) {
+            Some(funds) if funds.covers(self.cash.credit) => funds,
+            _ => self.cash.credit,
+        }
+    }
+
+    /// A selection has been made.  Cash credit is used if there is enough, otherwise
+    /// the card reader is asked to pay the whole price.  Age-restricted selections need
+    /// the customer to pass verification first.
+    pub fn request_vend<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        cashless: Option<&mut CashlessDevice>,
+        mut age_verifier: Option<&mut AgeVerificationDevice>,
+        bus: &mut Mdb<T>,
+        price: Money,
+        address: [u8; 2],
+    ) -> VendAuthorisation {
+        if self.cashless_request.is_some() {
+            defmt::debug!("Vend requested while the card reader is still deciding on the last");
+            return VendAuthorisation::AwaitingCashless;
+        }
+        if let Some(restrictions) = &self.age_restrictions {
+            if restrictions.minimum_age(address).is_some() {
+                let verified = match age_verifier.as_deref_mut() {
+                    Some(verifier) => verifier.authorise_selection(bus, restrictions, address),
+                    None => {
+                        defmt::error!("Age-restricted selection, but no age verification device");
+                        false
+                    }
+                };
+                if !verified {
+                    return VendAuthorisation::AgeNotVerified;
+                }
+            }
+        }
+
+        if self.cash.credit.covers(price) {
+            self.pending = Some(PendingVend {
+                method: PaymentMethod::Cash,
+                price,
+                address,
+            });
+            return VendAuthorisation::Approved(PaymentMethod::Cash);
+        }
+
+        let device = match cashless {
+            Some(device) => device,
+            None => return VendAuthorisation::InsufficientCredit,
+        };
+
+        let vend = PendingVend {
+            method: PaymentMethod::Cashless,
+            price,
+            address,
+        };
+        //In always idle mode the reader waits for the card itself, so the request can go now
+        if self.session.is_none() && device.always_idle_enabled {
+            self.session = Some(CashlessSession::new());
+        }
+        let mut request = CashlessRequest {
+            vend,
+            sent: false,
+            started_at: bus.timer.get_counter().ticks(),
+        };
+        if let Some(session) = self.session.as_ref() {
+            if let Err(outcome) = device.send_vend_request(bus, session, price, address) {
+                return self.cashless_outcome(vend, outcome, age_verifier);
+            }
+            request.sent = true;
+        }
+        self.cashless_request = Some(request);
+        VendAuthorisation::AwaitingCashless
+    }
+
+    /// The product was delivered - take the payment, and report cash sales to the reader.
+    pub fn vend_succeeded<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        cashless: Option<&mut CashlessDevice>,
+        age_verifier: Option<&mut AgeVerificationDevice>,
+        bus: &mut Mdb<T>,
+    ) -> bool {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => {
+                defmt::debug!("Vend success with no vend pending");
+                return false;
+            }
+        };
+        let result = self.take_payment(pending, cashless, bus);
+        self.clear_age_pass_if_done(age_verifier);
+        result
+    }
+
+    fn take_payment<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        pending: PendingVend,
+        cashless: Option<&mut CashlessDevice>,
+        bus: &mut Mdb<T>,
+    ) -> bool {
+        match pending.method {
+            PaymentMethod::Cash => {
+                self.cash.deduct(pending.price);
+                if let Some(device) = cashless {
+                    if device.supports_cash_sale_cmd {
+                        device.record_cash_transaction(bus, pending.price, pending.address);
+                    }
+                }
+                true
+            }
+            PaymentMethod::Cashless => match cashless {
+                Some(device) => device.vend_success(bus, pending.address),
+                None => false,
+            },
+        }
+    }
+
+    /// The product wasn't delivered - cash credit is kept, cashless funds are returned.
+    pub fn vend_failed<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        cashless: Option<&mut CashlessDevice>,
+        bus: &mut Mdb<T>,
+    ) -> bool {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return false,
+        };
+        match (pending.method, cashless, self.session.as_mut()) {
+            (PaymentMethod::Cash, _, _) => true,
+            (PaymentMethod::Cashless, Some(device), Some(session)) => {
+                device.session_vend_failed(bus, session)
+            }
+            (PaymentMethod::Cashless, Some(device), None) => device.vend_failed(bus),
+            (PaymentMethod::Cashless, None, _) => false,
+        }
+    }
+
+    /// The customer has finished - give them their change, and close the cashless session.
+    pub fn complete<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        acceptor: Option<&mut CoinAcceptor>,
+        cashless: Option<&mut CashlessDevice>,
+        age_verifier: Option<&mut AgeVerificationDevice>,
+        bus: &mut Mdb<T>,
+    ) -> Option<Refund> {
+        let request = self.cashless_request.take();
+        let session = self.session.take();
+        if let Some(device) = cashless {
+            if request.is_some_and(|request| request.sent) {
+                device.cancel_transaction(bus);
+            }
+            if let Some(mut session) = session {
+                device.finish_session(bus, &mut session);
+            }
+        }
+        if let Some(verifier) = age_verifier {
+            verifier.clear_verification();
+        }
+        match acceptor {
+            Some(acceptor) if !self.cash.credit.is_zero() => Some(self.cash.refund(acceptor, bus)),
+            _ => None,
+        }
+    }
+
+    //The customer has nothing left to spend, so their age pass goes too
+    fn clear_age_pass_if_done(&self, age_verifier: Option<&mut AgeVerificationDevice>) {
+        if self.session.is_none() && self.cash.credit.is_zero() {
+            if let Some(verifier) = age_verifier {
+                verifier.clear_verification();
+            }
+        }
+    }
+}
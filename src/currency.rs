@@ -0,0 +1,279 @@
+use defmt::Format;
+
+//MDB devices report their currency as 4 BCD digits.  A leading 0 means the rest is
+//the international telephone code of the country (eg 0x0044 for the UK), a leading 1
+//means the rest is the ISO 4217 numeric currency code (eg 0x1826 for GBP).
+//Both forms are decoded to the ISO 4217 currency, so devices using either agree.
+
+//ISO 4217 numeric code, alpha code and number of minor unit digits
+const ISO_CURRENCIES: [(u16, &str, u8); 46] = [
+    (32, "ARS", 2),
+    (36, "AUD", 2),
+    (48, "BHD", 3),
+    (124, "CAD", 2),
+    (152, "CLP", 0),
+    (156, "CNY", 2),
+    (170, "COP", 2),
+    (203, "CZK", 2),
+    (208, "DKK", 2),
+    (344, "HKD", 2),
+    (348, "HUF", 2),
+    (352, "ISK", 0),
+    (356, "INR", 2),
+    (360, "IDR", 2),
+    (376, "ILS", 2),
+    (392, "JPY", 0),
+    (400, "JOD", 3),
+    (404, "KES", 2),
+    (410, "KRW", 0),
+    (414, "KWD", 3),
+    (458, "MYR", 2),
+    (484, "MXN", 2),
+    (512, "OMR", 3),
+    (554, "NZD", 2),
+    (566, "NGN", 2),
+    (578, "NOK", 2),
+    (608, "PHP", 2),
+    (643, "RUB", 2),
+    (682, "SAR", 2),
+    (702, "SGD", 2),
+    (704, "VND", 0),
+    (710, "ZAR", 2),
+    (752, "SEK", 2),
+    (756, "CHF", 2),
+    (764, "THB", 2),
+    (784, "AED", 2),
+    (788, "TND", 3),
+    (818, "EGP", 2),
+    (826, "GBP", 2),
+    (840, "USD", 2),
+    (946, "RON", 2),
+    (949, "TRY", 2),
+    (978, "EUR", 2),
+    (980, "UAH", 2),
+    (985, "PLN", 2),
+    (986, "BRL", 2),
+];
+
+//Telephone country code, and the ISO 4217 numeric code of the currency used there
+const TELEPHONE_CODES: [(u16, u16); 46] = [
+    (1, 840),   //USA
+    (7, 643),   //Russia
+    (20, 818),  //Egypt
+    (27, 710),  //South Africa
+    (30, 978),  //Greece
+    (31, 978),  //Netherlands
+    (32, 978),  //Belgium
+    (33, 978),  //France
+    (34, 978),  //Spain
+    (36, 348),  //Hungary
+    (39, 978),  //Italy
+    (40, 946),  //Romania
+    (41, 756),  //Switzerland
+    (43, 978),  //Austria
+    (44, 826),  //UK
+    (45, 208),  //Denmark
+    (46, 752),  //Sweden
+    (47, 578),  //Norway
+    (48, 985),  //Poland
+    (49, 978),  //Germany
+    (52, 484),  //Mexico
+    (54, 32),   //Argentina
+    (55, 986),  //Brazil
+    (56, 152),  //Chile
+    (57, 170),  //Colombia
+    (60, 458),  //Malaysia
+    (61, 36),   //Australia
+    (62, 360),  //Indonesia
+    (63, 608),  //Philippines
+    (64, 554),  //New Zealand
+    (65, 702),  //Singapore
+    (66, 764),  //Thailand
+    (81, 392),  //Japan
+    (82, 410),  //South Korea
+    (84, 704),  //Vietnam
+    (86, 156),  //China
+    (90, 949),  //Turkey
+    (91, 356),  //India
+    (234, 566), //Nigeria
+    (254, 404), //Kenya
+    (351, 978), //Portugal
+    (353, 978), //Ireland
+    (354, 352), //Iceland
+    (358, 978), //Finland
+    (380, 980), //Ukraine
+    (420, 203), //Czech Republic
+];
+
+/// The currency a device works in
+#[derive(Copy, Clone, Debug, Format, PartialEq, Eq)]
+pub enum Currency {
+    //ISO 4217 numeric code
+    Iso(u16),
+    //A code we couldn't decode (bad BCD, or a telephone code we don't know) - as sent
+    Unknown(u16),
+}
+
+/// A device doesn't work in the machine's currency
+#[derive(Copy, Clone, Format)]
+pub struct CurrencyMismatch {
+    pub expected: Currency,
+    pub found: Currency,
+}
+
+impl Currency {
+    /// Decode the country/currency code from a device's setup reply
+    pub fn from_mdb_code(code: u16) -> Self {
+        let digits = match bcd_to_u16(code & 0x0FFF) {
+            Some(digits) => digits,
+            None => return Currency::Unknown(code),
+        };
+        match code >> 12 {
+            0x0 => match TELEPHONE_CODES.iter().find(|(tel, _)| *tel == digits) {
+                Some((_, numeric)) => Currency::Iso(*numeric),
+                None => Currency::Unknown(code),
+            },
+            0x1 => Currency::Iso(digits),
+            _ => Currency::Unknown(code),
+        }
+    }
+
+    /// The ISO 4217 form of the code, as a device would send it
+    pub fn to_mdb_code(&self) -> u16 {
+        match self {
+            Currency::Iso(numeric) => {
+                0x1000 | (numeric / 100) << 8 | (numeric / 10 % 10) << 4 | (numeric % 10)
+            }
+            Currency::Unknown(code) => *code,
+        }
+    }
+
+    pub fn numeric_code(&self) -> Option<u16> {
+        match self {
+            Currency::Iso(numeric) => Some(*numeric),
+            Currency::Unknown(_) => None,
+        }
+    }
+
+    /// Three letter ISO 4217 code, eg "EUR", if we know it
+    pub fn alpha_code(&self) -> Option<&'static str> {
+        self.lookup().map(|(_, alpha, _)| alpha)
+    }
+
+    /// Number of digits after the decimal point, eg 2 for EUR, 0 for JPY
+    pub fn minor_unit_digits(&self) -> Option<u8> {
+        self.lookup().map(|(_, _, digits)| digits)
+    }
+
+    /// Check a device works in this currency.  Device inits call this with the machine's
+    /// currency, and refuse a device that disagrees, so a mis-configured one is caught there.
+    pub fn check(&self, found: Currency) -> Result<(), CurrencyMismatch> {
+        if found != *self {
+            defmt::error!("Currency mismatch - device uses {}, expected {}", found, self);
+            return Err(CurrencyMismatch {
+                expected: *self,
+                found,
+            });
+        }
+        if let Currency::Unknown(code) = found {
+            defmt::info!("Currency code {=u16:#06x} not recognised", code);
+        }
+        Ok(())
+    }
+
+    fn lookup(&self) -> Option<(u16, &'static str, u8)> {
+        let numeric = self.numeric_code()?;
+        ISO_CURRENCIES.iter().find(|(n, _, _)| *n == numeric).copied()
+    }
+}
+
+/// Note if a device's decimal places don't match the currency - amounts are converted
+/// (see WireFormat), but the device may well be mis-configured
+pub fn check_decimal_places(currency: Currency, decimal_places: u8) -> bool {
+    match currency.minor_unit_digits() {
+        Some(digits) if digits != decimal_places => {
+            defmt::info!(
+                "Device reports {} decimal places, but {} has {}",
+                decimal_places,
+                currency,
+                digits
+            );
+            false
+        }
+        _ => true,
+    }
+}
+
+fn bcd_to_u16(bcd: u16) -> Option<u16> {
+    let mut value = 0;
+    for shift in [8, 4, 0] {
+        let digit = (bcd >> shift) & 0x0F;
+        if digit > 9 {
+            return None;
+        }
+        value = value * 10 + digit;
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    #[test]
+    fn iso_codes() {
+        assert_eq!(Currency::from_mdb_code(0x1840), Currency::Iso(840));
+        assert_eq!(Currency::from_mdb_code(0x1978), Currency::Iso(978));
+        assert_eq!(Currency::from_mdb_code(0x1826), Currency::Iso(826));
+        assert_eq!(Currency::Iso(826).alpha_code(), Some("GBP"));
+        assert_eq!(Currency::Iso(978).to_mdb_code(), 0x1978);
+    }
+
+    #[test]
+    fn telephone_codes() {
+        assert_eq!(Currency::from_mdb_code(0x0044), Currency::Iso(826));
+        assert_eq!(Currency::from_mdb_code(0x0001), Currency::Iso(840));
+        assert_eq!(Currency::from_mdb_code(0x0049), Currency::Iso(978));
+        //Reported in the ISO form, whichever was received
+        assert_eq!(Currency::from_mdb_code(0x0044).to_mdb_code(), 0x1826);
+    }
+
+    #[test]
+    fn unknown_codes() {
+        //Not BCD
+        assert_eq!(Currency::from_mdb_code(0x18A0), Currency::Unknown(0x18A0));
+        assert_eq!(Currency::from_mdb_code(0x004F), Currency::Unknown(0x004F));
+        //Telephone code we don't know
+        assert_eq!(Currency::from_mdb_code(0x0999), Currency::Unknown(0x0999));
+        //Neither form
+        assert_eq!(Currency::from_mdb_code(0x2840), Currency::Unknown(0x2840));
+
+        let unknown = Currency::Unknown(0x2840);
+        assert_eq!(unknown.to_mdb_code(), 0x2840);
+        assert_eq!(unknown.numeric_code(), None);
+        assert_eq!(unknown.alpha_code(), None);
+        assert_eq!(unknown.minor_unit_digits(), None);
+        //A valid ISO code that isn't in our table
+        assert_eq!(Currency::Iso(1).minor_unit_digits(), None);
+    }
+
+    #[test]
+    fn currency_check() {
+        let eur = Currency::Iso(978);
+        assert!(eur.check(Currency::from_mdb_code(0x0033)).is_ok());
+        let mismatch = eur.check(Currency::Iso(826)).unwrap_err();
+        assert_eq!(mismatch.expected, eur);
+        assert_eq!(mismatch.found, Currency::Iso(826));
+    }
+
+    #[test]
+    fn decimal_places() {
+        assert!(check_decimal_places(Currency::Iso(978), 2));
+        assert!(!check_decimal_places(Currency::Iso(978), 0));
+        assert!(check_decimal_places(Currency::Iso(392), 0));
+        assert!(!check_decimal_places(Currency::Iso(414), 2));
+        //Nothing to check against
+        assert!(check_decimal_places(Currency::Unknown(0x2840), 2));
+    }
+}
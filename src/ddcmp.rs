@@ -0,0 +1,557 @@
+use crate::audit::dex_crc16;
+
+use defmt::Format;
+
+//DDCMP link layer, as used by EVA-DTS handheld terminals to collect audit data over
+//the machine's audit port.  This is generic over any embedded_io transport, so it
+//can run over a UART on the machine, or a loopback/pty on a host for testing.
+//
+//Control messages are 8 bytes - ENQ, type, subtype/flags, receive count, send count,
+//station address, CRC (LSB first).
+//Data messages are an 8 byte header - SOH, count (14 bits, LSB first, top two bits
+//are flags), response number, message number, station address, header CRC - followed by
+//the data and its' own CRC.  Both CRCs are the same CRC-16 as the DEX G85 segment.
+//
+//Timeouts are left to the transport - a read which errors (or hits EOF) ends the exchange.
+
+const ENQ: u8 = 0x05;
+const SOH: u8 = 0x81;
+
+const CONTROL_ACK: u8 = 0x01;
+const CONTROL_NAK: u8 = 0x02;
+const CONTROL_START: u8 = 0x06;
+const CONTROL_STACK: u8 = 0x07;
+
+const FLAG_SELECT: u8 = 0x40;
+const STATION_ADDRESS: u8 = 0x01;
+
+//NAK reasons
+pub const DDCMP_NAK_HEADER_CRC: u8 = 0x01;
+pub const DDCMP_NAK_DATA_CRC: u8 = 0x02;
+pub const DDCMP_NAK_MESSAGE_TOO_LONG: u8 = 0x10;
+
+const MAX_RETRIES: u8 = 3;
+//Frames we will take while waiting for the link to come up, before giving up
+const MAX_ACCEPT_FRAMES: u8 = 10;
+//Bytes of noise we will skip looking for the start of a frame
+const MAX_NOISE: usize = 1024;
+
+//EVA-DTS application messages, carried in DDCMP data messages
+const EVA_COMMAND: u8 = 0x77;
+const EVA_RESPONSE: u8 = 0x88;
+const EVA_DATA: u8 = 0x99;
+const EVA_WHO_ARE_YOU: u8 = 0xE0;
+const EVA_READ_DATA: u8 = 0xE2;
+const EVA_FINIS: u8 = 0xFF;
+const EVA_AUDIT_LIST: u8 = 0x01;
+
+//Bytes of audit data per EVA-DTS data message
+pub const DDCMP_MAX_BLOCK: usize = 240;
+//Block numbers are a single byte, so this is the most audit data we can send
+pub const DDCMP_MAX_AUDIT_LENGTH: usize = 256 * DDCMP_MAX_BLOCK;
+//Largest data message we will accept from the other end
+const MAX_RECEIVE: usize = 64;
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum DdcmpFrame {
+    Start,
+    Stack,
+    //Number of the last data message the other end received
+    Ack(u8),
+    //Reason, and number of the last data message the other end received
+    Nak(u8, u8),
+    //Length of the data, now in the receive buffer
+    Data(usize),
+}
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum DdcmpError {
+    Io,
+    //Bad CRC - we have already NAKed it
+    Crc,
+    TooLong,
+    //A frame we weren't expecting at this point
+    Unexpected(DdcmpFrame),
+    RetriesExhausted,
+    //Nothing but noise from the other end
+    NoSync,
+}
+
+pub struct Ddcmp<T: embedded_io::Read + embedded_io::Write> {
+    pub io: T,
+    //Number of the last data message we sent, and the last we received
+    tx_num: u8,
+    rx_num: u8,
+}
+
+impl<T: embedded_io::Read + embedded_io::Write> Ddcmp<T> {
+    pub fn new(io: T) -> Self {
+        Self {
+            io,
+            tx_num: 0,
+            rx_num: 0,
+        }
+    }
+
+    /// Start the link from our end - START, wait for STACK, then ACK it
+    pub fn start(&mut self) -> Result<(), DdcmpError> {
+        let mut buf = [0x00u8; MAX_RECEIVE];
+        for _ in 0..MAX_RETRIES {
+            self.send_control(CONTROL_START, FLAG_SELECT, 0x00)?;
+            match self.receive(&mut buf) {
+                Ok(DdcmpFrame::Stack) => {
+                    self.reset_numbers();
+                    return self.send_control(CONTROL_ACK, FLAG_SELECT, 0x00);
+                }
+                Ok(frame) => defmt::debug!("DDCMP - expected STACK, got {}", frame),
+                Err(DdcmpError::Crc) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(DdcmpError::RetriesExhausted)
+    }
+
+    /// Wait for the other end to start the link.  Gives up if it hasn't come up after
+    /// MAX_ACCEPT_FRAMES frames - a silent line is left to the transport's read timeout.
+    pub fn accept(&mut self) -> Result<(), DdcmpError> {
+        let mut buf = [0x00u8; MAX_RECEIVE];
+        for _ in 0..MAX_ACCEPT_FRAMES {
+            match self.receive(&mut buf) {
+                Ok(DdcmpFrame::Start) => {
+                    self.send_control(CONTROL_STACK, FLAG_SELECT, 0x00)?;
+                    self.reset_numbers();
+                }
+                //The ACK of our STACK means the link is up
+                Ok(DdcmpFrame::Ack(0)) => return Ok(()),
+                Ok(frame) => defmt::debug!("DDCMP - waiting for START, got {}", frame),
+                Err(DdcmpError::Crc) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(DdcmpError::RetriesExhausted)
+    }
+
+    /// Send a data message, and wait for it to be ACKed, resending if it is NAKed
+    pub fn send_data(&mut self, data: &[u8]) -> Result<(), DdcmpError> {
+        if data.len() > 0x3FFF {
+            return Err(DdcmpError::TooLong);
+        }
+        let num = self.tx_num.wrapping_add(1);
+        let mut buf = [0x00u8; MAX_RECEIVE];
+        for _ in 0..MAX_RETRIES {
+            let header = [
+                SOH,
+                (data.len() & 0xFF) as u8,
+                ((data.len() >> 8) as u8 & 0x3F) | FLAG_SELECT,
+                self.rx_num,
+                num,
+                STATION_ADDRESS,
+            ];
+            self.write(&header)?;
+            self.write(&crc(&header).to_le_bytes())?;
+            self.write(data)?;
+            self.write(&crc(data).to_le_bytes())?;
+
+            match self.receive(&mut buf) {
+                Ok(DdcmpFrame::Ack(n)) if n == num => {
+                    self.tx_num = num;
+                    return Ok(());
+                }
+                Ok(DdcmpFrame::Nak(reason, _)) => {
+                    defmt::debug!("DDCMP - message {} NAKed, reason {}", num, reason)
+                }
+                Ok(frame) => return Err(DdcmpError::Unexpected(frame)),
+                Err(DdcmpError::Crc) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(DdcmpError::RetriesExhausted)
+    }
+
+    /// Wait for the next data message, ACK it, and return its' length.  Repeats of a
+    /// message we have already had are ACKed again and dropped.
+    pub fn receive_data(&mut self, buf: &mut [u8]) -> Result<usize, DdcmpError> {
+        let mut attempts = 0;
+        loop {
+            match self.receive(buf) {
+                Ok(DdcmpFrame::Data(len)) => return Ok(len),
+                //The other end restarted - STACK it and carry on
+                Ok(DdcmpFrame::Start) => {
+                    self.send_control(CONTROL_STACK, FLAG_SELECT, 0x00)?;
+                    self.reset_numbers();
+                }
+                Ok(DdcmpFrame::Ack(_)) => {}
+                Ok(frame) => return Err(DdcmpError::Unexpected(frame)),
+                Err(DdcmpError::Crc) => {
+                    attempts += 1;
+                    if attempts >= MAX_RETRIES {
+                        return Err(DdcmpError::RetriesExhausted);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Read the next frame.  Data messages are ACKed (or NAKed) here, as their message
+    /// number has to be checked anyway.
+    pub fn receive(&mut self, buf: &mut [u8]) -> Result<DdcmpFrame, DdcmpError> {
+        let mut noise: usize = 0;
+        loop {
+            let mut header = [0x00u8; 8];
+            header[0] = self.read_byte()?;
+            match header[0] {
+                ENQ => {
+                    self.read(&mut header[1..])?;
+                    if crc(&header[..6]).to_le_bytes() != header[6..8] {
+                        defmt::debug!("DDCMP - control message CRC error");
+                        return Err(DdcmpError::Crc);
+                    }
+                    return match header[1] {
+                        CONTROL_ACK => Ok(DdcmpFrame::Ack(header[3])),
+                        CONTROL_NAK => Ok(DdcmpFrame::Nak(header[2] & 0x3F, header[3])),
+                        CONTROL_START => Ok(DdcmpFrame::Start),
+                        CONTROL_STACK => Ok(DdcmpFrame::Stack),
+                        _ => {
+                            defmt::debug!("DDCMP - unknown control message {}", header[1]);
+                            continue;
+                        }
+                    };
+                }
+                SOH => {
+                    self.read(&mut header[1..])?;
+                    if crc(&header[..6]).to_le_bytes() != header[6..8] {
+                        self.send_control(CONTROL_NAK, DDCMP_NAK_HEADER_CRC, self.rx_num)?;
+                        return Err(DdcmpError::Crc);
+                    }
+                    let len = header[1] as usize | ((header[2] & 0x3F) as usize) << 8;
+                    if len > buf.len() {
+                        //Drain it, so we stay in step
+                        for _ in 0..len + 2 {
+                            self.read_byte()?;
+                        }
+                        self.send_control(CONTROL_NAK, DDCMP_NAK_MESSAGE_TOO_LONG, self.rx_num)?;
+                        return Err(DdcmpError::TooLong);
+                    }
+                    self.read(&mut buf[..len])?;
+                    let mut data_crc = [0x00u8; 2];
+                    self.read(&mut data_crc)?;
+                    if crc(&buf[..len]).to_le_bytes() != data_crc {
+                        self.send_control(CONTROL_NAK, DDCMP_NAK_DATA_CRC, self.rx_num)?;
+                        return Err(DdcmpError::Crc);
+                    }
+                    let num = header[4];
+                    if num != self.rx_num.wrapping_add(1) {
+                        //Probably a resend because our ACK got lost
+                        defmt::debug!("DDCMP - repeated message {}", num);
+                        self.send_control(CONTROL_ACK, FLAG_SELECT, self.rx_num)?;
+                        continue;
+                    }
+                    self.rx_num = num;
+                    self.send_control(CONTROL_ACK, FLAG_SELECT, self.rx_num)?;
+                    return Ok(DdcmpFrame::Data(len));
+                }
+                _ => {
+                    //Line noise, or we've lost sync - skip to the next frame
+                    noise += 1;
+                    if noise > MAX_NOISE {
+                        defmt::debug!("DDCMP - no frame found in {} bytes", noise);
+                        return Err(DdcmpError::NoSync);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Run the EVA-DTS audit collection as the machine end, sending dex (eg from
+    /// Audit::render_dex) when the handheld asks for it.
+    /// Returns true if all the data was sent, so the application can reset the
+    /// interim counters.
+    pub fn serve_audit(&mut self, dex: &[u8]) -> Result<bool, DdcmpError> {
+        if dex.len() > DDCMP_MAX_AUDIT_LENGTH {
+            defmt::error!(
+                "DDCMP - {} bytes of audit data is too long to send",
+                dex.len()
+            );
+            return Err(DdcmpError::TooLong);
+        }
+        self.accept()?;
+        let mut buf = [0x00u8; MAX_RECEIVE];
+        let mut sent = false;
+        loop {
+            let len = self.receive_data(&mut buf)?;
+            if len < 2 || buf[0] != EVA_COMMAND {
+                defmt::debug!("DDCMP - unexpected message {=[u8]:#04x}", buf[..len]);
+                continue;
+            }
+            match buf[1] {
+                EVA_WHO_ARE_YOU => {
+                    //Security/pass codes, date and time - we don't check or set any of them
+                    let mut reply = [0x00u8; 16];
+                    reply[0] = EVA_RESPONSE;
+                    reply[1] = EVA_WHO_ARE_YOU;
+                    self.send_data(&reply)?;
+                }
+                EVA_READ_DATA => {
+                    let list = if len > 3 { buf[3] } else { EVA_AUDIT_LIST };
+                    if list != EVA_AUDIT_LIST {
+                        defmt::info!("DDCMP - request for unsupported list {}", list);
+                        self.send_data(&[EVA_RESPONSE, EVA_READ_DATA, 0x01, list])?;
+                        continue;
+                    }
+                    let length = (dex.len() as u32).to_le_bytes();
+                    self.send_data(&[
+                        EVA_RESPONSE,
+                        EVA_READ_DATA,
+                        0x00,
+                        list,
+                        length[0],
+                        length[1],
+                        length[2],
+                        length[3],
+                    ])?;
+                    self.send_audit_blocks(dex)?;
+                    sent = true;
+                }
+                EVA_FINIS => {
+                    defmt::debug!("DDCMP - audit collection finished");
+                    return Ok(sent);
+                }
+                cmd => defmt::info!("DDCMP - unsupported EVA-DTS command {=u8:#04x}", cmd),
+            }
+        }
+    }
+
+    //Data messages - 0x99, block number, 0x01 if it's the last block, then the data
+    fn send_audit_blocks(&mut self, dex: &[u8]) -> Result<(), DdcmpError> {
+        let mut block = [0x00u8; DDCMP_MAX_BLOCK + 3];
+        let blocks = dex.len().div_ceil(DDCMP_MAX_BLOCK).max(1);
+        for i in 0..blocks {
+            let data = &dex[i * DDCMP_MAX_BLOCK..dex.len().min((i + 1) * DDCMP_MAX_BLOCK)];
+            block[0] = EVA_DATA;
+            block[1] = u8::try_from(i).map_err(|_| DdcmpError::TooLong)?;
+            block[2] = if i == blocks - 1 { 0x01 } else { 0x00 };
+            block[3..3 + data.len()].copy_from_slice(data);
+            self.send_data(&block[..3 + data.len()])?;
+        }
+        Ok(())
+    }
+
+    fn send_control(&mut self, kind: u8, subtype: u8, resp: u8) -> Result<(), DdcmpError> {
+        let mut msg = [ENQ, kind, subtype, resp, 0x00, STATION_ADDRESS, 0x00, 0x00];
+        if kind == CONTROL_NAK {
+            msg[2] |= FLAG_SELECT;
+        }
+        let c = crc(&msg[..6]).to_le_bytes();
+        msg[6] = c[0];
+        msg[7] = c[1];
+        self.write(&msg)
+    }
+
+    fn reset_numbers(&mut self) {
+        self.tx_num = 0;
+        self.rx_num = 0;
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), DdcmpError> {
+        self.io.write_all(bytes).map_err(|_| DdcmpError::Io)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), DdcmpError> {
+        self.io.read_exact(buf).map_err(|_| DdcmpError::Io)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, DdcmpError> {
+        let mut byte = [0x00u8; 1];
+        self.read(&mut byte)?;
+        Ok(byte[0])
+    }
+}
+
+fn crc(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0x0000, |crc, byte| dex_crc16(crc, *byte))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::Duration;
+    use std::vec::Vec;
+
+    //One end of a Linux socket pair, so each end of the link can run in its' own thread
+    struct Loopback(UnixStream);
+
+    impl embedded_io::ErrorType for Loopback {
+        type Error = embedded_io::ErrorKind;
+    }
+
+    impl embedded_io::Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            std::io::Read::read(&mut self.0, buf).map_err(|_| embedded_io::ErrorKind::TimedOut)
+        }
+    }
+
+    impl embedded_io::Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            std::io::Write::write(&mut self.0, buf).map_err(|_| embedded_io::ErrorKind::Other)
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn link() -> (Ddcmp<Loopback>, Ddcmp<Loopback>) {
+        let (a, b) = UnixStream::pair().unwrap();
+        for end in [&a, &b] {
+            end.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        }
+        (Ddcmp::new(Loopback(a)), Ddcmp::new(Loopback(b)))
+    }
+
+    fn control(kind: u8, subtype: u8, resp: u8) -> [u8; 8] {
+        let mut msg = [ENQ, kind, subtype, resp, 0x00, STATION_ADDRESS, 0x00, 0x00];
+        let c = crc(&msg[..6]).to_le_bytes();
+        msg[6] = c[0];
+        msg[7] = c[1];
+        msg
+    }
+
+    fn data_message(num: u8, data: &[u8]) -> Vec<u8> {
+        let header = [
+            SOH,
+            data.len() as u8,
+            FLAG_SELECT,
+            0x00,
+            num,
+            STATION_ADDRESS,
+        ];
+        let mut msg = Vec::from(header);
+        msg.extend_from_slice(&crc(&header).to_le_bytes());
+        msg.extend_from_slice(data);
+        msg.extend_from_slice(&crc(data).to_le_bytes());
+        msg
+    }
+
+    fn write_all(io: &mut Loopback, bytes: &[u8]) {
+        std::io::Write::write_all(&mut io.0, bytes).unwrap();
+    }
+
+    fn read_exact(io: &mut Loopback, len: usize) -> Vec<u8> {
+        let mut buf = std::vec![0x00u8; len];
+        std::io::Read::read_exact(&mut io.0, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn start_and_stack() {
+        let (mut handheld, mut machine) = link();
+        let machine = thread::spawn(move || machine.accept());
+        assert_eq!(handheld.start(), Ok(()));
+        assert_eq!(machine.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn data_is_acked() {
+        let (mut handheld, mut machine) = link();
+        let machine = thread::spawn(move || {
+            machine.accept()?;
+            let mut buf = [0x00u8; MAX_RECEIVE];
+            let mut received = Vec::new();
+            for _ in 0..2 {
+                let len = machine.receive_data(&mut buf)?;
+                received.push(Vec::from(&buf[..len]));
+            }
+            Ok::<_, DdcmpError>(received)
+        });
+        assert!(handheld.start().is_ok());
+        assert!(handheld.send_data(&[0x01, 0x02, 0x03]).is_ok());
+        assert!(handheld.send_data(&[0x04]).is_ok());
+        assert_eq!(
+            machine.join().unwrap(),
+            Ok(std::vec![std::vec![0x01, 0x02, 0x03], std::vec![0x04]])
+        );
+    }
+
+    #[test]
+    fn nak_is_retransmitted() {
+        let (mut sender, mut peer) = link();
+        let peer = thread::spawn(move || {
+            let first = read_exact(&mut peer.io, 8 + 3 + 2);
+            write_all(
+                &mut peer.io,
+                &control(CONTROL_NAK, DDCMP_NAK_DATA_CRC, 0x00),
+            );
+            let second = read_exact(&mut peer.io, 8 + 3 + 2);
+            write_all(&mut peer.io, &control(CONTROL_ACK, FLAG_SELECT, 0x01));
+            (first, second)
+        });
+        assert!(sender.send_data(&[0xAA, 0xBB, 0xCC]).is_ok());
+        let (first, second) = peer.join().unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn bad_crc_is_naked() {
+        let (mut receiver, mut peer) = link();
+        let peer = thread::spawn(move || {
+            let mut corrupt = data_message(0x01, &[0x10, 0x20]);
+            corrupt[9] ^= 0xFF;
+            write_all(&mut peer.io, &corrupt);
+            let nak = read_exact(&mut peer.io, 8);
+            write_all(&mut peer.io, &data_message(0x01, &[0x10, 0x20]));
+            let ack = read_exact(&mut peer.io, 8);
+            (nak, ack)
+        });
+        let mut buf = [0x00u8; MAX_RECEIVE];
+        assert_eq!(receiver.receive_data(&mut buf), Ok(2));
+        assert!(buf[..2] == [0x10, 0x20]);
+        let (nak, ack) = peer.join().unwrap();
+        assert!(nak[1] == CONTROL_NAK && nak[2] & 0x3F == DDCMP_NAK_DATA_CRC);
+        assert!(ack[1] == CONTROL_ACK && ack[3] == 0x01);
+    }
+
+    #[test]
+    fn serve_audit_sends_all_blocks() {
+        let dex: Vec<u8> = (0..500u32).map(|i| b'A' + (i % 26) as u8).collect();
+        let (mut handheld, mut machine) = link();
+        let served = dex.clone();
+        let machine = thread::spawn(move || machine.serve_audit(&served));
+
+        let mut buf = [0x00u8; DDCMP_MAX_BLOCK + 3];
+        assert!(handheld.start().is_ok());
+        assert!(handheld.send_data(&[EVA_COMMAND, EVA_WHO_ARE_YOU]).is_ok());
+        let len = handheld.receive_data(&mut buf).unwrap();
+        assert!(len == 16 && buf[..2] == [EVA_RESPONSE, EVA_WHO_ARE_YOU]);
+
+        assert!(handheld
+            .send_data(&[EVA_COMMAND, EVA_READ_DATA, 0x00, EVA_AUDIT_LIST])
+            .is_ok());
+        let len = handheld.receive_data(&mut buf).unwrap();
+        assert!(len == 8 && buf[2] == 0x00);
+        assert!(u32::from_le_bytes(buf[4..8].try_into().unwrap()) == 500);
+
+        let mut received = Vec::new();
+        for block in 0..3u8 {
+            let len = handheld.receive_data(&mut buf).unwrap();
+            assert!(buf[0] == EVA_DATA && buf[1] == block);
+            assert!((buf[2] == 0x01) == (block == 2));
+            received.extend_from_slice(&buf[3..len]);
+        }
+        assert_eq!(received, dex);
+
+        assert!(handheld.send_data(&[EVA_COMMAND, EVA_FINIS]).is_ok());
+        assert_eq!(machine.join().unwrap(), Ok(true));
+    }
+
+    #[test]
+    fn oversized_audit_is_refused() {
+        let dex = std::vec![0x00u8; DDCMP_MAX_AUDIT_LENGTH + 1];
+        let (_, mut machine) = link();
+        assert_eq!(machine.serve_audit(&dex), Err(DdcmpError::TooLong));
+    }
+}
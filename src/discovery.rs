@@ -0,0 +1,269 @@
+use crate::age_verification::AGE_VERIFICATION_ADDRESS;
+use crate::coin_hopper::{HOPPER_1_ADDRESS, HOPPER_2_ADDRESS};
+use crate::comms_gateway::COMMS_GATEWAY_ADDRESS;
+use crate::usd::{USD_1_ADDRESS, USD_2_ADDRESS, USD_3_ADDRESS};
+use crate::MDBResponse;
+use crate::Mdb;
+
+use defmt::Format;
+use embedded_hal::delay::DelayNs;
+
+//Bus enumeration - find out which peripherals are fitted, so the same firmware can run on
+//machines with different payment hardware.
+//
+//Each standard address is sent a RESET.  Anything that ACKs is polled to collect its' JUST
+//RESET, asked for its' setup (which gives its' feature level), then asked to identify itself
+//if it supports that.  Devices are left reset and unconfigured - the application should
+//still init the ones it wants to use, eg CoinAcceptor::init.
+
+const COIN_ACCEPTOR_ADDRESS: u8 = 0x08;
+const CASHLESS_1_ADDRESS: u8 = 0x10;
+const BILL_VALIDATOR_ADDRESS: u8 = 0x30;
+const CASHLESS_2_ADDRESS: u8 = 0x60;
+
+//Offsets from the device address
+const RESET_CMD: u8 = 0x00;
+const SETUP_CMD: u8 = 0x01;
+const POLL_CMD: u8 = 0x03;
+const EXPANSION_CMD: u8 = 0x07;
+const EXPANSION_REQUEST_ID: u8 = 0x00;
+
+//Cashless devices (and the peripherals that followed their command layout) poll at 0x02
+const CASHLESS_POLL_CMD: u8 = 0x02;
+
+//Cashless and comms gateway SETUP carry the VMC's config
+const SETUP_CONFIG_DATA: u8 = 0x00;
+const VMC_FEATURE_LEVEL: u8 = 0x01;
+
+const RESET_RECOVERY_MS: u32 = 100;
+
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum PeripheralKind {
+    CoinAcceptor,
+    Cashless1,
+    CommsGateway,
+    BillValidator,
+    Usd1,
+    Usd2,
+    Usd3,
+    Hopper1,
+    Cashless2,
+    AgeVerification,
+    Hopper2,
+}
+
+pub const ALL_PERIPHERALS: [PeripheralKind; 11] = [
+    PeripheralKind::CoinAcceptor,
+    PeripheralKind::Cashless1,
+    PeripheralKind::CommsGateway,
+    PeripheralKind::BillValidator,
+    PeripheralKind::Usd1,
+    PeripheralKind::Usd2,
+    PeripheralKind::Usd3,
+    PeripheralKind::Hopper1,
+    PeripheralKind::Cashless2,
+    PeripheralKind::AgeVerification,
+    PeripheralKind::Hopper2,
+];
+
+impl PeripheralKind {
+    pub fn address(&self) -> u8 {
+        match self {
+            PeripheralKind::CoinAcceptor => COIN_ACCEPTOR_ADDRESS,
+            PeripheralKind::Cashless1 => CASHLESS_1_ADDRESS,
+            PeripheralKind::CommsGateway => COMMS_GATEWAY_ADDRESS,
+            PeripheralKind::BillValidator => BILL_VALIDATOR_ADDRESS,
+            PeripheralKind::Usd1 => USD_1_ADDRESS,
+            PeripheralKind::Usd2 => USD_2_ADDRESS,
+            PeripheralKind::Usd3 => USD_3_ADDRESS,
+            PeripheralKind::Hopper1 => HOPPER_1_ADDRESS,
+            PeripheralKind::Cashless2 => CASHLESS_2_ADDRESS,
+            PeripheralKind::AgeVerification => AGE_VERIFICATION_ADDRESS,
+            PeripheralKind::Hopper2 => HOPPER_2_ADDRESS,
+        }
+    }
+
+    fn poll_cmd(&self) -> u8 {
+        self.address()
+            | match self {
+                PeripheralKind::Cashless1
+                | PeripheralKind::Cashless2
+                | PeripheralKind::CommsGateway
+                | PeripheralKind::AgeVerification => CASHLESS_POLL_CMD,
+                _ => POLL_CMD,
+            }
+    }
+
+    //Returns the message and its' length
+    fn setup_msg(&self) -> ([u8; 6], usize) {
+        let setup = self.address() | SETUP_CMD;
+        match self {
+            PeripheralKind::Cashless1 | PeripheralKind::Cashless2 => (
+                //No display
+                [
+                    setup,
+                    SETUP_CONFIG_DATA,
+                    VMC_FEATURE_LEVEL,
+                    0x00,
+                    0x00,
+                    0x00,
+                ],
+                6,
+            ),
+            //Scale factor 1, 2 decimal places - it is set up properly at init
+            PeripheralKind::CommsGateway => (
+                [
+                    setup,
+                    SETUP_CONFIG_DATA,
+                    VMC_FEATURE_LEVEL,
+                    0x01,
+                    0x02,
+                    0x00,
+                ],
+                5,
+            ),
+            _ => ([setup, 0x00, 0x00, 0x00, 0x00, 0x00], 1),
+        }
+    }
+
+    //Where the feature level is in the setup reply
+    fn level_offset(&self) -> usize {
+        match self {
+            PeripheralKind::Cashless1
+            | PeripheralKind::Cashless2
+            | PeripheralKind::CommsGateway => 1,
+            _ => 0,
+        }
+    }
+
+    //Where the manufacturer code starts in the identification reply
+    fn ident_offset(&self) -> usize {
+        match self {
+            PeripheralKind::CoinAcceptor
+            | PeripheralKind::BillValidator
+            | PeripheralKind::Hopper1
+            | PeripheralKind::Hopper2 => 0,
+            //Preceded by a peripheral ID reply byte
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct PeripheralIdentification {
+    pub manufacturer_code: [u8; 3],
+    pub serial_number: [u8; 12],
+    pub model_number: [u8; 12],
+    pub software_version: [u8; 2],
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct DiscoveredPeripheral {
+    pub kind: PeripheralKind,
+    pub feature_level: u8,
+    //Not every device (or feature level) can identify itself
+    pub identification: Option<PeripheralIdentification>,
+}
+
+/// The peripherals found by Mdb::discover
+#[derive(Copy, Clone, Format)]
+pub struct BusInventory {
+    //In the same order as ALL_PERIPHERALS
+    pub peripherals: [Option<DiscoveredPeripheral>; 11],
+}
+
+impl BusInventory {
+    pub fn get(&self, kind: PeripheralKind) -> Option<&DiscoveredPeripheral> {
+        self.iter().find(|p| p.kind == kind)
+    }
+
+    pub fn is_present(&self, kind: PeripheralKind) -> bool {
+        self.get(kind).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DiscoveredPeripheral> {
+        self.peripherals.iter().flatten()
+    }
+
+    pub fn count(&self) -> usize {
+        self.iter().count()
+    }
+}
+
+impl<T: embedded_io::Write + embedded_io::Read> Mdb<T> {
+    /// Probe every standard peripheral address, and return what answered.  Takes a while
+    /// (one timeout for every address with nothing on it), so call at startup.
+    pub fn discover(&mut self) -> BusInventory {
+        let mut inventory = BusInventory {
+            peripherals: [None; 11],
+        };
+        for (i, kind) in ALL_PERIPHERALS.iter().enumerate() {
+            inventory.peripherals[i] = self.probe(*kind);
+            match &inventory.peripherals[i] {
+                Some(peripheral) => defmt::info!("Found {}", peripheral),
+                None => defmt::debug!("No {} on the bus", kind),
+            }
+        }
+        inventory
+    }
+
+    /// Reset a single peripheral, and collect its' feature level and identification
+    pub fn probe(&mut self, kind: PeripheralKind) -> Option<DiscoveredPeripheral> {
+        //Anything there should ACK the reset
+        if !self.send_data_and_confirm_ack(&[kind.address() | RESET_CMD]) {
+            return None;
+        }
+        self.timer.delay_ms(RESET_RECOVERY_MS);
+
+        let mut buf: [u8; 36] = [0x00; 36];
+        self.send_data(&[kind.poll_cmd()]);
+        self.receive_response(&mut buf);
+
+        let (setup, setup_len) = kind.setup_msg();
+        self.send_data(&setup[..setup_len]);
+        let feature_level = match self.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count > kind.level_offset() => buf[kind.level_offset()],
+            _ => {
+                defmt::debug!(
+                    "{} at {=u8:#04x} ACKed reset but gave no setup",
+                    kind,
+                    kind.address()
+                );
+                return None;
+            }
+        };
+
+        Some(DiscoveredPeripheral {
+            kind,
+            feature_level,
+            identification: self.request_identification(kind),
+        })
+    }
+
+    fn request_identification(&mut self, kind: PeripheralKind) -> Option<PeripheralIdentification> {
+        //Cashless devices expect the VMC's identification in the request - we send blanks
+        let mut msg: [u8; 31] = [0x00; 31];
+        msg[0] = kind.address() | EXPANSION_CMD;
+        msg[1] = EXPANSION_REQUEST_ID;
+        let len = match kind {
+            PeripheralKind::Cashless1 | PeripheralKind::Cashless2 => 31,
+            _ => 2,
+        };
+        self.send_data(&msg[..len]);
+
+        let mut buf: [u8; 36] = [0x00; 36];
+        let offset = kind.ident_offset();
+        match self.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count >= offset + 29 => {
+                let id = &buf[offset..offset + 29];
+                Some(PeripheralIdentification {
+                    manufacturer_code: id[0..3].try_into().unwrap(),
+                    serial_number: id[3..15].try_into().unwrap(),
+                    model_number: id[15..27].try_into().unwrap(),
+                    software_version: id[27..29].try_into().unwrap(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
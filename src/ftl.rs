@@ -0,0 +1,656 @@
+//MDB File Transport Layer (FTL)
+//
+//FTL lets the VMC and a peripheral exchange files (eg config or firmware blocks)
+//in 31 byte blocks.  The commands are the same for every device - only the
+//command prefix (eg 0x17 for cashless, 0x0F for the coin changer) differs, and the
+//peripheral's replies arrive in its' poll response.  Any device that can hand back
+//FTL replies from its' poll can implement FtlDevice and use the functions here.
+//
+//The transfer only needs to send commands and wait, which it does through FtlTransport.
+//Mdb implements that, and so can a simulated peripheral, so transfers can be tested off
+//the target.
+
+use crate::Mdb;
+
+use defmt::Format;
+use embedded_hal::delay::DelayNs;
+
+//FTL commands, sent after the device's command prefix
+const FTL_REQ_TO_RCV: u8 = 0xFA;
+const FTL_RETRY_DENY: u8 = 0xFB;
+const FTL_SEND_BLOCK: u8 = 0xFC;
+const FTL_OK_TO_SEND: u8 = 0xFD;
+const FTL_REQ_TO_SEND: u8 = 0xFE;
+
+//FTL replies, found in the peripheral's poll response
+pub const FTL_REPLY_REQ_TO_RCV: u8 = 0x1B;
+pub const FTL_REPLY_RETRY_DENY: u8 = 0x1C;
+pub const FTL_REPLY_SEND_BLOCK: u8 = 0x1D;
+pub const FTL_REPLY_OK_TO_SEND: u8 = 0x1E;
+pub const FTL_REPLY_REQ_TO_SEND: u8 = 0x1F;
+
+//The VMC's address, as used for the source/destination of FTL messages
+pub const FTL_VMC_ADDRESS: u8 = 0x00;
+
+//Files are sent in blocks of this many bytes
+pub const FTL_BLOCK_SIZE: usize = 31;
+
+//A retry delay of this value means the request is denied outright
+const FTL_DENY: u8 = 0xFF;
+//Retry delays are given in seconds
+const FTL_RETRY_DELAY_UNIT_MS: u32 = 1000;
+//How many times we will retry a request the peripheral asked us to retry
+const FTL_MAX_RETRIES: u8 = 3;
+
+//How long we wait for the peripheral to reply during a transfer (100 * 50mS = 5 seconds)
+const FTL_MAX_POLLS: u16 = 100;
+const FTL_POLL_INTERVAL_MS: u32 = 50;
+
+/// A request to send or receive a file
+#[derive(Copy, Clone, Format)]
+pub struct FtlFileRequest {
+    pub destination: u8,
+    pub source: u8,
+    pub file_id: u8,
+    pub max_length: u8, //In blocks
+    pub control: u8,
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct FtlBlock {
+    pub destination: u8,
+    pub block_number: u8,
+    pub len: usize,
+    pub data: [u8; FTL_BLOCK_SIZE],
+}
+
+#[derive(Copy, Clone, Format)]
+pub enum FtlReply {
+    //The peripheral wants a file from the VMC
+    ReqToRcv(FtlFileRequest),
+    RetryDeny {
+        destination: u8,
+        source: u8,
+        retry_delay: u8,
+    },
+    SendBlock(FtlBlock),
+    OkToSend {
+        destination: u8,
+        source: u8,
+    },
+    //The peripheral wants to send a file to the VMC
+    ReqToSend(FtlFileRequest),
+}
+
+/// What an FTL transfer needs from the bus
+pub trait FtlTransport {
+    /// Send a command, returning true if the peripheral ACKed it
+    fn send_data_and_confirm_ack(&mut self, msg: &[u8]) -> bool;
+    fn delay_ms(&mut self, ms: u32);
+}
+
+impl<T: embedded_io::Write + embedded_io::Read> FtlTransport for Mdb<T> {
+    fn send_data_and_confirm_ack(&mut self, msg: &[u8]) -> bool {
+        Mdb::send_data_and_confirm_ack(self, msg)
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.timer.delay_ms(ms);
+    }
+}
+
+/// A device that can take part in an FTL transfer over bus B
+pub trait FtlDevice<B: FtlTransport> {
+    /// The device's MDB address, used as the source/destination of FTL messages
+    fn ftl_address(&self) -> u8;
+    /// The prefix its' FTL commands are sent with
+    fn ftl_command_prefix(&self) -> u8;
+    /// Poll the device, returning the FTL reply if it sent one
+    fn ftl_poll(&mut self, bus: &mut B) -> Option<FtlReply>;
+}
+
+/// Given the first byte of an FTL reply, return its' length.  Send block replies
+/// are variable length (up to 33 bytes), so the maximum is returned for those.
+pub fn reply_length(reply_cmd: u8) -> usize {
+    match reply_cmd {
+        FTL_REPLY_REQ_TO_RCV | FTL_REPLY_REQ_TO_SEND => 6,
+        FTL_REPLY_RETRY_DENY => 4,
+        FTL_REPLY_SEND_BLOCK => 3 + FTL_BLOCK_SIZE,
+        FTL_REPLY_OK_TO_SEND => 3,
+        _ => 1,
+    }
+}
+
+pub fn is_ftl_reply(reply_cmd: u8) -> bool {
+    (FTL_REPLY_REQ_TO_RCV..=FTL_REPLY_REQ_TO_SEND).contains(&reply_cmd)
+}
+
+/// Parse an FTL reply, starting at its' reply code
+pub fn parse_reply(reply: &[u8]) -> Option<FtlReply> {
+    if reply.len() < 3 {
+        defmt::debug!("FTL reply too short: {=[u8]:#04x}", reply);
+        return None;
+    }
+    match reply[0] {
+        FTL_REPLY_REQ_TO_RCV | FTL_REPLY_REQ_TO_SEND => {
+            if reply.len() < 6 {
+                defmt::debug!("FTL request too short: {=[u8]:#04x}", reply);
+                return None;
+            }
+            let request = FtlFileRequest {
+                destination: reply[1],
+                source: reply[2],
+                file_id: reply[3],
+                max_length: reply[4],
+                control: reply[5],
+            };
+            if reply[0] == FTL_REPLY_REQ_TO_RCV {
+                Some(FtlReply::ReqToRcv(request))
+            } else {
+                Some(FtlReply::ReqToSend(request))
+            }
+        }
+        FTL_REPLY_RETRY_DENY => {
+            if reply.len() < 4 {
+                defmt::debug!("FTL retry/deny too short: {=[u8]:#04x}", reply);
+                return None;
+            }
+            Some(FtlReply::RetryDeny {
+                destination: reply[1],
+                source: reply[2],
+                retry_delay: reply[3],
+            })
+        }
+        FTL_REPLY_SEND_BLOCK => {
+            let len = (reply.len() - 3).min(FTL_BLOCK_SIZE);
+            let mut data = [0x00; FTL_BLOCK_SIZE];
+            data[0..len].copy_from_slice(&reply[3..3 + len]);
+            Some(FtlReply::SendBlock(FtlBlock {
+                destination: reply[1],
+                block_number: reply[2],
+                len,
+                data,
+            }))
+        }
+        FTL_REPLY_OK_TO_SEND => Some(FtlReply::OkToSend {
+            destination: reply[1],
+            source: reply[2],
+        }),
+        _ => None,
+    }
+}
+
+/// Ask the peripheral to send us a file.  The file is written into buf, and its' length returned.
+pub fn receive_file<B: FtlTransport, D: FtlDevice<B>>(
+    device: &mut D,
+    bus: &mut B,
+    file_id: u8,
+    control: u8,
+    buf: &mut [u8],
+) -> Option<usize> {
+    let max_length = (buf.len() / FTL_BLOCK_SIZE).min(0xFF) as u8;
+
+    for _ in 0..=FTL_MAX_RETRIES {
+        if !bus.send_data_and_confirm_ack(&[
+            device.ftl_command_prefix(),
+            FTL_REQ_TO_RCV,
+            device.ftl_address(),
+            FTL_VMC_ADDRESS,
+            file_id,
+            max_length,
+            control,
+        ]) {
+            defmt::debug!("FTL request to receive not acked");
+            return None;
+        }
+        match collect_blocks(device, bus, max_length, buf) {
+            BlockResult::Complete(len) => return Some(len),
+            BlockResult::Retry(delay) => bus.delay_ms(delay as u32 * FTL_RETRY_DELAY_UNIT_MS),
+            BlockResult::Failed => return None,
+        }
+    }
+    defmt::debug!("FTL receive gave up after retries");
+    None
+}
+
+/// Send a file to the peripheral
+pub fn send_file<B: FtlTransport, D: FtlDevice<B>>(
+    device: &mut D,
+    bus: &mut B,
+    file_id: u8,
+    control: u8,
+    data: &[u8],
+) -> bool {
+    let num_blocks = data.len().div_ceil(FTL_BLOCK_SIZE);
+    if num_blocks > 0xFF {
+        defmt::debug!("File too long to send by FTL");
+        return false;
+    }
+
+    for _ in 0..=FTL_MAX_RETRIES {
+        if !bus.send_data_and_confirm_ack(&[
+            device.ftl_command_prefix(),
+            FTL_REQ_TO_SEND,
+            device.ftl_address(),
+            FTL_VMC_ADDRESS,
+            file_id,
+            num_blocks as u8,
+            control,
+        ]) {
+            defmt::debug!("FTL request to send not acked");
+            return false;
+        }
+
+        //Wait for the peripheral to say OK or ask us to retry
+        let mut retry_delay = None;
+        let mut ok_to_send = false;
+        for _ in 0..FTL_MAX_POLLS {
+            match device.ftl_poll(bus) {
+                Some(FtlReply::OkToSend { .. }) => {
+                    ok_to_send = true;
+                    break;
+                }
+                Some(FtlReply::RetryDeny { retry_delay: delay, .. }) => {
+                    retry_delay = Some(delay);
+                    break;
+                }
+                Some(_) => {
+                    defmt::debug!("Unexpected FTL reply while waiting for OK to send");
+                }
+                None => {}
+            }
+            bus.delay_ms(FTL_POLL_INTERVAL_MS);
+        }
+
+        if ok_to_send {
+            return send_blocks(device, bus, data);
+        }
+        match retry_delay {
+            Some(FTL_DENY) => {
+                defmt::debug!("FTL request to send denied");
+                return false;
+            }
+            Some(delay) => bus.delay_ms(delay as u32 * FTL_RETRY_DELAY_UNIT_MS),
+            None => {
+                defmt::debug!("Peripheral did not reply to FTL request to send");
+                return false;
+            }
+        }
+    }
+    defmt::debug!("FTL send gave up after retries");
+    false
+}
+
+/// The peripheral has asked to send us a file (FtlReply::ReqToSend) - accept it.
+pub fn accept_file<B: FtlTransport, D: FtlDevice<B>>(
+    device: &mut D,
+    bus: &mut B,
+    request: &FtlFileRequest,
+    buf: &mut [u8],
+) -> Option<usize> {
+    if request.max_length as usize * FTL_BLOCK_SIZE > buf.len() {
+        defmt::debug!("FTL file offered is too large for buffer");
+        deny(device, bus, request, FTL_DENY);
+        return None;
+    }
+    if !bus.send_data_and_confirm_ack(&[
+        device.ftl_command_prefix(),
+        FTL_OK_TO_SEND,
+        request.source,
+        FTL_VMC_ADDRESS,
+    ]) {
+        defmt::debug!("FTL OK to send not acked");
+        return None;
+    }
+    match collect_blocks(device, bus, request.max_length, buf) {
+        BlockResult::Complete(len) => Some(len),
+        _ => None,
+    }
+}
+
+/// The peripheral has asked us for a file (FtlReply::ReqToRcv) - send it.
+pub fn serve_file<B: FtlTransport, D: FtlDevice<B>>(
+    device: &mut D,
+    bus: &mut B,
+    request: &FtlFileRequest,
+    data: &[u8],
+) -> bool {
+    if data.len() > request.max_length as usize * FTL_BLOCK_SIZE {
+        defmt::debug!("FTL file requested is larger than the peripheral will accept");
+        deny(device, bus, request, FTL_DENY);
+        return false;
+    }
+    send_blocks(device, bus, data)
+}
+
+/// Refuse (retry_delay 0xFF) or postpone a request the peripheral made
+pub fn deny<B: FtlTransport, D: FtlDevice<B>>(
+    device: &mut D,
+    bus: &mut B,
+    request: &FtlFileRequest,
+    retry_delay: u8,
+) -> bool {
+    bus.send_data_and_confirm_ack(&[
+        device.ftl_command_prefix(),
+        FTL_RETRY_DENY,
+        request.source,
+        FTL_VMC_ADDRESS,
+        retry_delay,
+    ])
+}
+
+fn send_blocks<B: FtlTransport, D: FtlDevice<B>>(device: &mut D, bus: &mut B, data: &[u8]) -> bool {
+    let mut msg: [u8; 4 + FTL_BLOCK_SIZE] = [0x00; 4 + FTL_BLOCK_SIZE];
+    msg[0] = device.ftl_command_prefix();
+    msg[1] = FTL_SEND_BLOCK;
+    msg[2] = device.ftl_address();
+
+    for (block_number, block) in data.chunks(FTL_BLOCK_SIZE).enumerate() {
+        msg[3] = block_number as u8;
+        msg[4..4 + block.len()].copy_from_slice(block);
+        if !bus.send_data_and_confirm_ack(&msg[0..4 + block.len()]) {
+            defmt::debug!("FTL block {} not acked", block_number);
+            return false;
+        }
+    }
+    true
+}
+
+enum BlockResult {
+    Complete(usize),
+    Retry(u8),
+    Failed,
+}
+
+/// Poll the peripheral for the blocks of a file.  The transfer is complete when
+/// max_blocks have been received, or a short block arrives.
+fn collect_blocks<B: FtlTransport, D: FtlDevice<B>>(
+    device: &mut D,
+    bus: &mut B,
+    max_blocks: u8,
+    buf: &mut [u8],
+) -> BlockResult {
+    let mut expected_block: u8 = 0;
+    let mut len: usize = 0;
+    let mut polls: u16 = 0;
+
+    while polls < FTL_MAX_POLLS {
+        match device.ftl_poll(bus) {
+            Some(FtlReply::SendBlock(block)) => {
+                if block.block_number != expected_block {
+                    defmt::debug!(
+                        "FTL block out of order - expected {}, got {}",
+                        expected_block,
+                        block.block_number
+                    );
+                    return BlockResult::Failed;
+                }
+                if len + block.len > buf.len() {
+                    defmt::debug!("FTL file too large for buffer");
+                    return BlockResult::Failed;
+                }
+                buf[len..len + block.len].copy_from_slice(&block.data[0..block.len]);
+                len += block.len;
+                expected_block = expected_block.wrapping_add(1);
+
+                if block.len < FTL_BLOCK_SIZE || expected_block == max_blocks {
+                    return BlockResult::Complete(len);
+                }
+                //Got a block, so restart the timeout
+                polls = 0;
+                continue;
+            }
+            Some(FtlReply::RetryDeny { retry_delay, .. }) => {
+                if retry_delay == FTL_DENY || expected_block != 0 {
+                    defmt::debug!("FTL transfer denied");
+                    return BlockResult::Failed;
+                }
+                return BlockResult::Retry(retry_delay);
+            }
+            Some(_) => {
+                defmt::debug!("Unexpected FTL reply while receiving blocks");
+            }
+            None => {}
+        }
+        polls += 1;
+        bus.delay_ms(FTL_POLL_INTERVAL_MS);
+    }
+    defmt::debug!("Timed out waiting for FTL block {}", expected_block);
+    BlockResult::Failed
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    const PREFIX: u8 = 0x17;
+    const ADDRESS: u8 = 0x10;
+
+    //Records what was sent, and ACKs everything
+    #[derive(Default)]
+    struct FakeBus {
+        sent: Vec<Vec<u8>>,
+        delayed_ms: u32,
+    }
+
+    impl FtlTransport for FakeBus {
+        fn send_data_and_confirm_ack(&mut self, msg: &[u8]) -> bool {
+            self.sent.push(Vec::from(msg));
+            true
+        }
+
+        fn delay_ms(&mut self, ms: u32) {
+            self.delayed_ms += ms;
+        }
+    }
+
+    //Gives the scripted replies to successive polls, then nothing
+    struct FakePeripheral {
+        replies: VecDeque<Option<FtlReply>>,
+    }
+
+    impl FakePeripheral {
+        fn new(replies: &[Option<FtlReply>]) -> Self {
+            Self {
+                replies: replies.iter().copied().collect(),
+            }
+        }
+    }
+
+    impl FtlDevice<FakeBus> for FakePeripheral {
+        fn ftl_address(&self) -> u8 {
+            ADDRESS
+        }
+
+        fn ftl_command_prefix(&self) -> u8 {
+            PREFIX
+        }
+
+        fn ftl_poll(&mut self, _bus: &mut FakeBus) -> Option<FtlReply> {
+            self.replies.pop_front().flatten()
+        }
+    }
+
+    fn block(block_number: u8, bytes: &[u8]) -> Option<FtlReply> {
+        let mut data = [0x00; FTL_BLOCK_SIZE];
+        data[..bytes.len()].copy_from_slice(bytes);
+        Some(FtlReply::SendBlock(FtlBlock {
+            destination: FTL_VMC_ADDRESS,
+            block_number,
+            len: bytes.len(),
+            data,
+        }))
+    }
+
+    fn retry_deny(retry_delay: u8) -> Option<FtlReply> {
+        Some(FtlReply::RetryDeny {
+            destination: FTL_VMC_ADDRESS,
+            source: ADDRESS,
+            retry_delay,
+        })
+    }
+
+    fn file(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn receive_multiple_blocks() {
+        let data = file(70);
+        let mut device = FakePeripheral::new(&[
+            None,
+            block(0, &data[0..31]),
+            block(1, &data[31..62]),
+            None,
+            block(2, &data[62..70]),
+        ]);
+        let mut bus = FakeBus::default();
+        let mut buf = [0x00; 4 * FTL_BLOCK_SIZE];
+
+        assert_eq!(
+            receive_file(&mut device, &mut bus, 0x05, 0x00, &mut buf),
+            Some(70)
+        );
+        assert_eq!(buf[..70], data[..]);
+        assert_eq!(
+            bus.sent,
+            [[
+                PREFIX,
+                FTL_REQ_TO_RCV,
+                ADDRESS,
+                FTL_VMC_ADDRESS,
+                0x05,
+                4,
+                0x00
+            ]]
+        );
+    }
+
+    #[test]
+    fn receive_out_of_order_block_fails() {
+        let data = file(62);
+        let mut device = FakePeripheral::new(&[block(0, &data[0..31]), block(2, &data[31..62])]);
+        let mut bus = FakeBus::default();
+        let mut buf = [0x00; 4 * FTL_BLOCK_SIZE];
+
+        assert_eq!(
+            receive_file(&mut device, &mut bus, 0x05, 0x00, &mut buf),
+            None
+        );
+    }
+
+    #[test]
+    fn receive_retries_after_delay() {
+        let data = file(10);
+        let mut device = FakePeripheral::new(&[retry_deny(2), block(0, &data)]);
+        let mut bus = FakeBus::default();
+        let mut buf = [0x00; 2 * FTL_BLOCK_SIZE];
+
+        assert_eq!(
+            receive_file(&mut device, &mut bus, 0x05, 0x00, &mut buf),
+            Some(10)
+        );
+        assert_eq!(buf[..10], data[..]);
+        //Asked twice, waiting the retry delay in between
+        assert_eq!(bus.sent.len(), 2);
+        assert_eq!(bus.sent[0], bus.sent[1]);
+        assert!(bus.delayed_ms >= 2 * FTL_RETRY_DELAY_UNIT_MS);
+    }
+
+    #[test]
+    fn receive_gives_up_after_retries() {
+        let retries = [retry_deny(1); FTL_MAX_RETRIES as usize + 1];
+        let mut device = FakePeripheral::new(&retries);
+        let mut bus = FakeBus::default();
+        let mut buf = [0x00; 2 * FTL_BLOCK_SIZE];
+
+        assert_eq!(
+            receive_file(&mut device, &mut bus, 0x05, 0x00, &mut buf),
+            None
+        );
+        assert_eq!(bus.sent.len(), FTL_MAX_RETRIES as usize + 1);
+    }
+
+    #[test]
+    fn send_in_blocks() {
+        let data = file(40);
+        let mut device = FakePeripheral::new(&[
+            None,
+            Some(FtlReply::OkToSend {
+                destination: FTL_VMC_ADDRESS,
+                source: ADDRESS,
+            }),
+        ]);
+        let mut bus = FakeBus::default();
+
+        assert!(send_file(&mut device, &mut bus, 0x07, 0x00, &data));
+        assert_eq!(bus.sent.len(), 3);
+        assert_eq!(
+            bus.sent[0],
+            [
+                PREFIX,
+                FTL_REQ_TO_SEND,
+                ADDRESS,
+                FTL_VMC_ADDRESS,
+                0x07,
+                2,
+                0x00
+            ]
+        );
+        assert_eq!(bus.sent[1][..4], [PREFIX, FTL_SEND_BLOCK, ADDRESS, 0]);
+        assert_eq!(bus.sent[1][4..], data[0..31]);
+        assert_eq!(bus.sent[2][..4], [PREFIX, FTL_SEND_BLOCK, ADDRESS, 1]);
+        assert_eq!(bus.sent[2][4..], data[31..40]);
+    }
+
+    #[test]
+    fn send_denied() {
+        let mut device = FakePeripheral::new(&[retry_deny(FTL_DENY)]);
+        let mut bus = FakeBus::default();
+
+        assert!(!send_file(&mut device, &mut bus, 0x07, 0x00, &file(40)));
+        //Just the request - no blocks
+        assert_eq!(bus.sent.len(), 1);
+    }
+
+    #[test]
+    fn send_retries_after_delay() {
+        let mut device = FakePeripheral::new(&[
+            retry_deny(3),
+            Some(FtlReply::OkToSend {
+                destination: FTL_VMC_ADDRESS,
+                source: ADDRESS,
+            }),
+        ]);
+        let mut bus = FakeBus::default();
+
+        assert!(send_file(&mut device, &mut bus, 0x07, 0x00, &file(20)));
+        assert_eq!(bus.sent.len(), 3);
+        assert_eq!(bus.sent[0], bus.sent[1]);
+        assert!(bus.delayed_ms >= 3 * FTL_RETRY_DELAY_UNIT_MS);
+    }
+
+    #[test]
+    fn oversized_offer_is_denied() {
+        let mut device = FakePeripheral::new(&[]);
+        let mut bus = FakeBus::default();
+        let request = FtlFileRequest {
+            destination: FTL_VMC_ADDRESS,
+            source: ADDRESS,
+            file_id: 0x01,
+            max_length: 3,
+            control: 0x00,
+        };
+        let mut buf = [0x00; 2 * FTL_BLOCK_SIZE];
+
+        assert_eq!(accept_file(&mut device, &mut bus, &request, &mut buf), None);
+        assert_eq!(
+            bus.sent,
+            [[PREFIX, FTL_RETRY_DENY, ADDRESS, FTL_VMC_ADDRESS, FTL_DENY]]
+        );
+    }
+}
@@ -1,10 +1,41 @@
 #![no_std]
 
+pub mod age_verification;
+pub mod audit;
 pub mod coin_acceptor;
+pub mod coin_escrow;
+pub mod coin_health;
+pub mod coin_hopper;
+pub mod coin_inventory;
+pub mod comms_gateway;
 pub mod cashless_device;
+pub mod credit;
+pub mod currency;
+pub mod ddcmp;
+pub mod discovery;
+pub mod ftl;
+pub mod money;
+pub mod presence;
+pub mod usd;
 
 use enumn::N;
 
+//defmt needs a logger for the host tests to link - it doesn't log anywhere
+#[cfg(test)]
+mod test_logger {
+    #[defmt::global_logger]
+    struct NullLogger;
+
+    unsafe impl defmt::Logger for NullLogger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("");
+}
+
 //this had to be increased, because the Nayax is slow to reply sometimes!
 const MDB_TIMEOUT_MS: u8 = 100;
 
@@ -28,14 +59,41 @@ pub struct Mdb<T: embedded_io::Write + embedded_io::Read> {
     uart: T, //The 9 bit uart that we will use to read write MDB
     pub timer: rp2040_hal::timer::Timer,
     //Should we include other settings, eg timeout?
+    //Address the last message was sent to, so we know who a reply came from
+    last_address: u8,
+    //When each address (indexed by address >> 3) last replied - 64 bit timer counter, uS
+    last_reply: [Option<u64>; 32],
 }
 
 impl<T: embedded_io::Write + embedded_io::Read> Mdb<T> {
     pub fn new(uart: T, timer: rp2040_hal::timer::Timer) -> Self {
-        Self { uart, timer }
+        Self {
+            uart,
+            timer,
+            last_address: 0x00,
+            last_reply: [None; 32],
+        }
+    }
+
+    /// How long since the peripheral at address replied to anything, in mS.  None if it
+    /// never has.
+    pub fn silent_for_ms(&self, address: u8) -> Option<u64> {
+        self.last_reply[(address >> 3) as usize]
+            .map(|last| self.timer.get_counter().ticks().saturating_sub(last) / 1000)
     }
 
     pub fn receive_response(&mut self, buf: &mut [u8]) -> MDBResponse<usize, MDBStatus> {
+        let response = self.receive_message(buf);
+        //Even a bad checksum means something is there
+        if !matches!(response, MDBResponse::StatusMsg(MDBStatus::NoReply)) {
+            self.last_reply[(self.last_address >> 3) as usize] =
+                Some(self.timer.get_counter().ticks());
+        }
+        response
+    }
+
+    #[allow(unused_variables, clippy::single_match, clippy::collapsible_match)]
+    fn receive_message(&mut self, buf: &mut [u8]) -> MDBResponse<usize, MDBStatus> {
         //We need a scratch buffer twice the maximum message length, because
         //2 bytes are returned by the 9 bit uart, with the first byte holding the ninth bit val.
         let mut scratch_buf: [u8; 72] = [0x00; 72];
@@ -134,6 +192,9 @@ impl<T: embedded_io::Write + embedded_io::Read> Mdb<T> {
         //It's a normal message, so needs a checksum
         let mut checksum: u8 = 0x00;
         let mut is_first_byte = true;
+        if let Some(address) = msg.first() {
+            self.last_address = *address;
+        }
 
         for i in msg.iter() {
             //First byte is an address byte, 9th bit high
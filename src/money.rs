@@ -0,0 +1,237 @@
+use crate::currency::Currency;
+
+use defmt::Format;
+
+//MDB devices send money as a multiple of their own scaling factor, which differs from
+//device to device.  Money is always held in the smallest unit of the currency (eg cents),
+//and each device converts to and from its' own wire format at the point it talks to the bus.
+//
+//A device's wire format is its' currency, scaling factor and decimal places - a wire value
+//of 1 is worth scale_factor / 10^decimal_places of the currency's main unit.  Devices
+//normally use the currency's own decimal places, but where they don't, the amount is
+//converted.  For a currency we don't know, the device's decimal places are taken as right.
+
+/// How a device sends money on the bus, from its' setup reply
+#[derive(Copy, Clone, Format, PartialEq, Eq)]
+pub struct WireFormat {
+    pub currency: Currency,
+    pub scale_factor: u8,
+    pub decimal_places: u8,
+}
+
+impl WireFormat {
+    pub fn new(currency: Currency, scale_factor: u8, decimal_places: u8) -> Self {
+        Self {
+            currency,
+            scale_factor,
+            decimal_places,
+        }
+    }
+
+    //The currency's decimal places less the device's - minor units per device unit is 10^this
+    fn decimal_shift(&self) -> i32 {
+        let digits = self
+            .currency
+            .minor_unit_digits()
+            .unwrap_or(self.decimal_places);
+        digits as i32 - self.decimal_places as i32
+    }
+}
+
+/// An amount in the smallest unit of a currency, and the currency
+#[derive(Copy, Clone, Debug, Format, PartialEq, Eq)]
+pub struct Money {
+    pub minor_units: u32,
+    pub currency: Currency,
+}
+
+impl Money {
+    pub const fn new(minor_units: u32, currency: Currency) -> Self {
+        Self {
+            minor_units,
+            currency,
+        }
+    }
+
+    pub const fn zero(currency: Currency) -> Self {
+        Self::new(0, currency)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.minor_units == 0
+    }
+
+    /// None if the currencies differ, or on overflow
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        Some(Money::new(
+            self.minor_units.checked_add(other.minor_units)?,
+            self.currency,
+        ))
+    }
+
+    /// None if the currencies differ, or other is larger
+    pub fn checked_sub(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        Some(Money::new(
+            self.minor_units.checked_sub(other.minor_units)?,
+            self.currency,
+        ))
+    }
+
+    /// Like checked_add, but stops at the maximum.  None (and logged) if the
+    /// currencies differ - mixing them is a bug.
+    pub fn saturating_add(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            defmt::error!("Cannot add {} to {} - currencies differ", other, self);
+            return None;
+        }
+        Some(Money::new(
+            self.minor_units.saturating_add(other.minor_units),
+            self.currency,
+        ))
+    }
+
+    /// Like checked_sub, but stops at zero.  None (and logged) if the
+    /// currencies differ - mixing them is a bug.
+    pub fn saturating_sub(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            defmt::error!(
+                "Cannot subtract {} from {} - currencies differ",
+                other,
+                self
+            );
+            return None;
+        }
+        Some(Money::new(
+            self.minor_units.saturating_sub(other.minor_units),
+            self.currency,
+        ))
+    }
+
+    /// This amount count times over, eg the value of a tube of coins
+    pub fn saturating_mul(self, count: u32) -> Money {
+        Money::new(self.minor_units.saturating_mul(count), self.currency)
+    }
+
+    /// Whether self is at least other (false if the currencies differ)
+    pub fn covers(&self, other: Money) -> bool {
+        self.currency == other.currency && self.minor_units >= other.minor_units
+    }
+
+    /// Convert to a device's scaled wire value.  None if the currency is wrong, on
+    /// overflow, or if the amount isn't a whole number of scaling units - sending it
+    /// rounded would over- or under-charge.
+    pub fn to_scaled(self, format: WireFormat) -> Option<u32> {
+        let units = self.device_units(format, false)?;
+        if format.scale_factor == 0 || !units.is_multiple_of(format.scale_factor as u64) {
+            return None;
+        }
+        u32::try_from(units / format.scale_factor as u64).ok()
+    }
+
+    /// Like to_scaled, but rounds down to a whole number of scaling units, eg for the
+    /// most change a device can pay out of this amount
+    pub fn to_scaled_floor(self, format: WireFormat) -> Option<u32> {
+        let units = self.device_units(format, true)?;
+        if format.scale_factor == 0 {
+            return None;
+        }
+        u32::try_from(units / format.scale_factor as u64).ok()
+    }
+
+    /// Convert a scaled wire value from a device.  Anything finer than the currency's
+    /// smallest unit is dropped.
+    pub fn from_scaled(scaled: u32, format: WireFormat) -> Money {
+        let units = scaled as u64 * format.scale_factor as u64;
+        let shift = format.decimal_shift();
+        let minor_units = if shift >= 0 {
+            units.saturating_mul(10u64.saturating_pow(shift as u32))
+        } else {
+            units / 10u64.saturating_pow(shift.unsigned_abs())
+        };
+        Money::new(
+            u32::try_from(minor_units).unwrap_or(u32::MAX),
+            format.currency,
+        )
+    }
+
+    //The amount in the device's own units (before scaling).  None if the currency is wrong,
+    //or (unless rounding down) the amount is finer than the device can represent.
+    fn device_units(self, format: WireFormat, round_down: bool) -> Option<u64> {
+        if self.currency != format.currency {
+            return None;
+        }
+        let shift = format.decimal_shift();
+        let minor_units = self.minor_units as u64;
+        if shift >= 0 {
+            let per_unit = 10u64.checked_pow(shift as u32)?;
+            if !round_down && !minor_units.is_multiple_of(per_unit) {
+                return None;
+            }
+            Some(minor_units / per_unit)
+        } else {
+            minor_units.checked_mul(10u64.checked_pow(shift.unsigned_abs())?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    const EUR: Currency = Currency::Iso(978);
+    const JPY: Currency = Currency::Iso(392);
+
+    #[test]
+    fn scales_to_and_from_the_wire() {
+        let format = WireFormat::new(EUR, 5, 2);
+        assert_eq!(Money::new(250, EUR).to_scaled(format), Some(50));
+        assert_eq!(Money::from_scaled(50, format), Money::new(250, EUR));
+        //Not a whole number of scaling units
+        assert_eq!(Money::new(252, EUR).to_scaled(format), None);
+        assert_eq!(Money::new(252, EUR).to_scaled_floor(format), Some(50));
+        //Wrong currency
+        assert_eq!(Money::new(250, JPY).to_scaled(format), None);
+    }
+
+    #[test]
+    fn converts_device_decimal_places() {
+        //A changer counting whole euros
+        let whole_euros = WireFormat::new(EUR, 1, 0);
+        assert_eq!(Money::from_scaled(2, whole_euros), Money::new(200, EUR));
+        assert_eq!(Money::new(200, EUR).to_scaled(whole_euros), Some(2));
+        assert_eq!(Money::new(250, EUR).to_scaled(whole_euros), None);
+        assert_eq!(Money::new(250, EUR).to_scaled_floor(whole_euros), Some(2));
+
+        //A device sending yen with two decimal places
+        let yen_hundredths = WireFormat::new(JPY, 1, 2);
+        assert_eq!(
+            Money::from_scaled(1000, yen_hundredths),
+            Money::new(10, JPY)
+        );
+        assert_eq!(Money::new(10, JPY).to_scaled(yen_hundredths), Some(1000));
+    }
+
+    #[test]
+    fn unknown_currency_uses_device_decimal_places() {
+        let currency = Currency::Unknown(0x0999);
+        let format = WireFormat::new(currency, 10, 3);
+        assert_eq!(Money::from_scaled(7, format), Money::new(70, currency));
+        assert_eq!(Money::new(70, currency).to_scaled(format), Some(7));
+    }
+
+    #[test]
+    fn mixed_currencies_do_not_add() {
+        assert_eq!(Money::new(1, EUR).saturating_add(Money::new(1, JPY)), None);
+        assert_eq!(
+            Money::new(1, EUR).saturating_sub(Money::new(2, EUR)),
+            Some(Money::zero(EUR))
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use crate::discovery::PeripheralKind;
+use crate::Mdb;
+
+use defmt::Format;
+
+//Online/offline tracking for a peripheral.  A device is offline once it hasn't replied to
+//anything for longer than its' non-response time (any reply counts, including ACKs).  While
+//it is offline, its' init is retried every so often, so it can be unplugged and plugged back
+//in without rebooting the machine.
+//
+//The device still has to be polled as usual - a device which isn't being talked to will
+//look like it has gone away.
+//
+//  let mut coin_presence = PresenceMonitor::new(PeripheralKind::CoinAcceptor);
+//  let mut coin = CoinAcceptor::init(&mut bus, currency);
+//  loop {
+//      if let Some(acceptor) = coin.as_mut() {
+//          acceptor.poll(&mut bus);
+//      }
+//      coin_presence.poll(&mut bus, &mut coin, |bus| CoinAcceptor::init(bus, currency));
+//  }
+
+//Default time between attempts to bring an offline device back
+const DEFAULT_PROBE_INTERVAL_MS: u32 = 5000;
+
+#[derive(Copy, Clone, Format)]
+pub enum PresenceEvent {
+    //Stopped replying - the device has been dropped
+    WentOffline(PeripheralKind),
+    //Replied to a probe, and has been re-initialised
+    CameBack(PeripheralKind),
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct PresenceMonitor {
+    pub kind: PeripheralKind,
+    pub non_response_ms: u32,
+    pub probe_interval_ms: u32,
+    pub online: bool,
+    last_probe: Option<u64>, //64 bit timer counter, uS
+}
+
+impl PresenceMonitor {
+    pub fn new(kind: PeripheralKind) -> Self {
+        Self {
+            kind,
+            non_response_ms: default_non_response_ms(kind),
+            probe_interval_ms: DEFAULT_PROBE_INTERVAL_MS,
+            online: true,
+            last_probe: None,
+        }
+    }
+
+    /// Override the spec's non-response time, eg with the maximum response time the device
+    /// reported in its' setup
+    pub fn non_response_ms(mut self, ms: u32) -> Self {
+        self.non_response_ms = ms;
+        self
+    }
+
+    pub fn probe_interval_ms(mut self, interval: u32) -> Self {
+        self.probe_interval_ms = interval;
+        self
+    }
+
+    /// Call from the main loop, after polling the device.  Drops the device (sets it to None)
+    /// when it goes offline, and while it is offline, calls init every probe interval until
+    /// it comes back.
+    pub fn poll<T, D, F>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        device: &mut Option<D>,
+        init: F,
+    ) -> Option<PresenceEvent>
+    where
+        T: embedded_io::Write + embedded_io::Read,
+        F: FnOnce(&mut Mdb<T>) -> Option<D>,
+    {
+        if device.is_some() {
+            self.online = true;
+            if self.timed_out(bus) {
+                defmt::info!("{} has gone offline", self.kind);
+                self.online = false;
+                self.last_probe = Some(bus.timer.get_counter().ticks());
+                *device = None;
+                return Some(PresenceEvent::WentOffline(self.kind));
+            }
+            return None;
+        }
+
+        //Offline (or never found) - try again if it's time
+        self.online = false;
+        let now = bus.timer.get_counter().ticks();
+        if let Some(last) = self.last_probe {
+            if now.saturating_sub(last) < self.probe_interval_ms as u64 * 1000 {
+                return None;
+            }
+        }
+        self.last_probe = Some(now);
+        *device = init(bus);
+        if device.is_some() {
+            defmt::info!("{} is back online", self.kind);
+            self.online = true;
+            return Some(PresenceEvent::CameBack(self.kind));
+        }
+        None
+    }
+
+    fn timed_out<T: embedded_io::Write + embedded_io::Read>(&self, bus: &Mdb<T>) -> bool {
+        match bus.silent_for_ms(self.kind.address()) {
+            Some(silent) => silent > self.non_response_ms as u64,
+            //Never replied, so init can't have succeeded on this bus
+            None => true,
+        }
+    }
+}
+
+/// Non-response times from the MDB spec
+pub fn default_non_response_ms(kind: PeripheralKind) -> u32 {
+    match kind {
+        PeripheralKind::CoinAcceptor | PeripheralKind::Hopper1 | PeripheralKind::Hopper2 => 2000,
+        _ => 5000,
+    }
+}
@@ -0,0 +1,550 @@
+use crate::cashless_device::CashlessDevice;
+use crate::currency;
+use crate::currency::Currency;
+use crate::money::{Money, WireFormat};
+use crate::MDBResponse;
+use crate::Mdb;
+
+use defmt::Format;
+use embedded_hal::delay::DelayNs;
+
+//Universal Satellite Devices drive the vend mechanisms (spirals, lifts etc) of a modular
+//machine.  There can be three on the bus, and their commands are offsets from their
+//address.  A vend either starts at the USD (the customer chooses on its' keypad, and it
+//asks us to approve the price), or at the VMC, which tells the USD which item to vend.
+//Either way, the USD reports success or failure in its' poll replies.
+
+pub const USD_1_ADDRESS: u8 = 0x40;
+pub const USD_2_ADDRESS: u8 = 0x48;
+pub const USD_3_ADDRESS: u8 = 0x50;
+
+const RESET_CMD: u8 = 0x00;
+const SETUP_CMD: u8 = 0x01;
+const VEND_CMD: u8 = 0x02;
+const POLL_CMD: u8 = 0x03;
+const FUNDS_CMD: u8 = 0x04;
+const CONTROL_CMD: u8 = 0x05;
+const EXPANSION_CMD: u8 = 0x07;
+
+//Vend sub-commands
+const VEND_APPROVED: u8 = 0x00;
+const VEND_DISAPPROVED: u8 = 0x01;
+const VEND_SELECTION: u8 = 0x02;
+const VEND_HOME_SELECTION: u8 = 0x03;
+const VEND_SELECTION_STATUS: u8 = 0x04;
+
+//Control sub-commands
+const CONTROL_DISABLE: u8 = 0x00;
+const CONTROL_ENABLE: u8 = 0x01;
+const CONTROL_HOME: u8 = 0x02;
+const CONTROL_POSITION: u8 = 0x03;
+
+//Expansion sub-commands
+const EXPANSION_REQUEST_ID: u8 = 0x00;
+const EXPANSION_SET_PRICE: u8 = 0x02;
+
+//Poll replies
+const POLL_REPLY_JUST_RESET: u8 = 0x00;
+const POLL_REPLY_VEND_REQUEST: u8 = 0x01;
+const POLL_REPLY_VEND_SUCCESS: u8 = 0x02;
+const POLL_REPLY_VEND_FAILED: u8 = 0x03;
+const POLL_REPLY_SELECTION_STATUS: u8 = 0x04;
+const POLL_REPLY_HOME_COMPLETE: u8 = 0x05;
+const POLL_REPLY_MALFUNCTION: u8 = 0x06;
+const POLL_REPLY_PERIPHERAL_ID: u8 = 0x09;
+
+//Setup option bits
+const OPTION_HAS_KEYPAD: u8 = 0x01;
+const OPTION_NEEDS_PRICES: u8 = 0x02;
+
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum UsdNumber {
+    Usd1,
+    Usd2,
+    Usd3,
+}
+
+impl UsdNumber {
+    pub fn address(&self) -> u8 {
+        match self {
+            UsdNumber::Usd1 => USD_1_ADDRESS,
+            UsdNumber::Usd2 => USD_2_ADDRESS,
+            UsdNumber::Usd3 => USD_3_ADDRESS,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum UsdFailure {
+    SoldOut,
+    Jam,
+    HomeSensor,
+    DeliverySensor,
+    //Any other (manufacturer specific) code
+    Other(u8),
+}
+
+impl UsdFailure {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => UsdFailure::SoldOut,
+            0x02 => UsdFailure::Jam,
+            0x03 => UsdFailure::HomeSensor,
+            0x04 => UsdFailure::DeliverySensor,
+            other => UsdFailure::Other(other),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Format)]
+pub enum UsdPollEvent {
+    JustReset,
+    //The USD reset unexpectedly, and we have re-initialised it (true if successful).
+    //Poll returns this in place of JustReset.
+    Reinitialised(bool),
+    //The customer chose an item on the USD - approve_vend or deny_vend it
+    VendRequest([u8; 2], Money),
+    VendSucceeded([u8; 2]),
+    VendFailed([u8; 2], UsdFailure),
+    //Item, and the USD's (manufacturer specific) status for it
+    SelectionStatus([u8; 2], u8),
+    HomeComplete,
+    Malfunction(u8),
+    //Any reply we don't (yet) do anything with
+    Unhandled(u8),
+}
+
+/// How a vend went, ready to pass on to the card reader if it paid
+#[derive(Copy, Clone, Format)]
+pub enum UsdVendResult {
+    Succeeded([u8; 2]),
+    Failed([u8; 2], UsdFailure),
+    //The USD never told us - treat it as failed
+    TimedOut([u8; 2]),
+}
+
+impl UsdVendResult {
+    pub fn succeeded(&self) -> bool {
+        matches!(self, UsdVendResult::Succeeded(_))
+    }
+
+    /// Tell the card reader how the vend went - vend_success or vend_failed
+    pub fn report_to_cashless<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        device: &CashlessDevice,
+        bus: &mut Mdb<T>,
+    ) -> bool {
+        match self {
+            UsdVendResult::Succeeded(address) => device.vend_success(bus, *address),
+            UsdVendResult::Failed(..) | UsdVendResult::TimedOut(_) => device.vend_failed(bus),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct UsdIdentification {
+    pub manufacturer_code: [u8; 3],
+    pub serial_number: [u8; 12],
+    pub model: [u8; 12],
+    pub software_version: [u8; 2],
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct UniversalSatelliteDevice {
+    pub address: u8,
+    pub feature_level: u8,
+    pub country_code: u16,
+    pub scale_factor: u8,
+    pub decimal_places: u8,
+    pub max_response_time: u8, //Seconds
+    pub selections: u16,
+    //Customers choose items on the USD itself, so it will send vend requests
+    pub has_keypad: bool,
+    //The USD doesn't store prices, so they must be set with set_price
+    pub needs_prices: bool,
+    pub identification: Option<UsdIdentification>,
+    pub enabled: bool,
+}
+
+impl UniversalSatelliteDevice {
+    /// currency is the one the machine works in - a USD set up for another is refused
+    pub fn init<T: embedded_io::Write + embedded_io::Read>(
+        bus: &mut Mdb<T>,
+        number: UsdNumber,
+        currency: Currency,
+    ) -> Option<Self> {
+        let address = number.address();
+        bus.send_data(&[address | RESET_CMD]);
+        bus.timer.delay_ms(100);
+
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[address | POLL_CMD]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(_) if buf[0] == POLL_REPLY_JUST_RESET => {}
+            _ => {
+                defmt::debug!("No reset reply from USD at {=u8:#04x}", address);
+                return None;
+            }
+        }
+
+        let mut usd = Self::setup(bus, address)?;
+        currency.check(usd.currency()).ok()?;
+        usd.identification = usd.identify(bus);
+        usd.set_enabled(bus, true);
+        Some(usd)
+    }
+
+    fn setup<T: embedded_io::Write + embedded_io::Read>(
+        bus: &mut Mdb<T>,
+        address: u8,
+    ) -> Option<Self> {
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[address | SETUP_CMD]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count >= 9 => {}
+            _ => {
+                defmt::debug!("Invalid setup reply from USD at {=u8:#04x}", address);
+                return None;
+            }
+        }
+        let usd = Self {
+            address,
+            feature_level: buf[0],
+            country_code: (buf[1] as u16) << 8 | buf[2] as u16,
+            scale_factor: buf[3],
+            decimal_places: buf[4],
+            max_response_time: buf[5],
+            selections: (buf[6] as u16) << 8 | buf[7] as u16,
+            has_keypad: buf[8] & OPTION_HAS_KEYPAD != 0,
+            needs_prices: buf[8] & OPTION_NEEDS_PRICES != 0,
+            identification: None,
+            enabled: false,
+        };
+        let currency = usd.currency();
+        defmt::debug!("USD {=u8:#04x} currency {}", address, currency);
+        currency::check_decimal_places(currency, usd.decimal_places);
+        Some(usd)
+    }
+
+    fn reinitialise<T: embedded_io::Write + embedded_io::Read>(&mut self, bus: &mut Mdb<T>) -> bool {
+        defmt::info!("USD {=u8:#04x} reset unexpectedly - re-initialising", self.address);
+        match Self::setup(bus, self.address) {
+            Some(usd) => {
+                //Prices would be wrong in a different currency
+                if self.currency().check(usd.currency()).is_err() {
+                    return false;
+                }
+                let enabled = self.enabled;
+                let identification = self.identification;
+                *self = usd;
+                self.identification = identification;
+                self.set_enabled(bus, enabled)
+            }
+            None => {
+                defmt::error!("USD failed to re-initialise");
+                false
+            }
+        }
+    }
+
+    pub fn identify<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+    ) -> Option<UsdIdentification> {
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[self.address | EXPANSION_CMD, EXPANSION_REQUEST_ID]);
+        match bus.receive_response(&mut buf) {
+            MDBResponse::Data(count) if count >= 30 && buf[0] == POLL_REPLY_PERIPHERAL_ID => {
+                Some(UsdIdentification {
+                    manufacturer_code: buf[1..4].try_into().unwrap(),
+                    serial_number: buf[4..16].try_into().unwrap(),
+                    model: buf[16..28].try_into().unwrap(),
+                    software_version: buf[28..30].try_into().unwrap(),
+                })
+            }
+            _ => {
+                defmt::debug!("USD did not identify itself");
+                None
+            }
+        }
+    }
+
+    pub fn set_enabled<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        enable: bool,
+    ) -> bool {
+        self.enabled = enable;
+        let sub = if enable { CONTROL_ENABLE } else { CONTROL_DISABLE };
+        bus.send_data_and_confirm_ack(&[self.address | CONTROL_CMD, sub])
+    }
+
+    /// Poll the USD, and split its' reply into individual events
+    pub fn poll<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+    ) -> [Option<UsdPollEvent>; 8] {
+        let mut poll_results: [Option<UsdPollEvent>; 8] = [None; 8];
+        let mut result_count: usize = 0;
+
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(&[self.address | POLL_CMD]);
+        if let MDBResponse::Data(count) = bus.receive_response(&mut buf) {
+            let mut index = 0;
+            while index < count && result_count < poll_results.len() {
+                let len = poll_reply_length(buf[index]).min(count - index);
+                poll_results[result_count] = Some(self.parse_poll_reply(&buf[index..index + len]));
+                result_count += 1;
+                index += len;
+            }
+        }
+
+        for result in poll_results.iter_mut() {
+            if matches!(result, Some(UsdPollEvent::JustReset)) {
+                *result = Some(UsdPollEvent::Reinitialised(self.reinitialise(bus)));
+            }
+        }
+        poll_results
+    }
+
+    fn parse_poll_reply(&self, reply: &[u8]) -> UsdPollEvent {
+        let item = |reply: &[u8]| -> [u8; 2] {
+            if reply.len() >= 3 {
+                [reply[1], reply[2]]
+            } else {
+                [0x00, 0x00]
+            }
+        };
+        match reply[0] {
+            POLL_REPLY_JUST_RESET => UsdPollEvent::JustReset,
+            POLL_REPLY_VEND_REQUEST if reply.len() >= 5 => UsdPollEvent::VendRequest(
+                item(reply),
+                self.from_wire((reply[3] as u16) << 8 | reply[4] as u16),
+            ),
+            POLL_REPLY_VEND_SUCCESS => UsdPollEvent::VendSucceeded(item(reply)),
+            POLL_REPLY_VEND_FAILED if reply.len() >= 4 => {
+                UsdPollEvent::VendFailed(item(reply), UsdFailure::from_code(reply[3]))
+            }
+            POLL_REPLY_SELECTION_STATUS if reply.len() >= 4 => {
+                UsdPollEvent::SelectionStatus(item(reply), reply[3])
+            }
+            POLL_REPLY_HOME_COMPLETE => UsdPollEvent::HomeComplete,
+            POLL_REPLY_MALFUNCTION if reply.len() >= 2 => UsdPollEvent::Malfunction(reply[1]),
+            other => {
+                defmt::debug!("Unhandled poll reply from USD: {=[u8]:#04x}", reply);
+                UsdPollEvent::Unhandled(other)
+            }
+        }
+    }
+
+    /// Let a vend the customer chose on the USD go ahead
+    pub fn approve_vend<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        address: [u8; 2],
+    ) -> bool {
+        bus.send_data_and_confirm_ack(&[
+            self.address | VEND_CMD,
+            VEND_APPROVED,
+            address[0],
+            address[1],
+        ])
+    }
+
+    pub fn deny_vend<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        address: [u8; 2],
+    ) -> bool {
+        bus.send_data_and_confirm_ack(&[
+            self.address | VEND_CMD,
+            VEND_DISAPPROVED,
+            address[0],
+            address[1],
+        ])
+    }
+
+    /// Tell the USD to vend an item (eg once the card reader has approved it)
+    pub fn vend<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        address: [u8; 2],
+    ) -> bool {
+        bus.send_data_and_confirm_ack(&[
+            self.address | VEND_CMD,
+            VEND_SELECTION,
+            address[0],
+            address[1],
+        ])
+    }
+
+    /// Wait for the USD to report how a vend went, after vend or approve_vend
+    pub fn wait_for_vend<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        address: [u8; 2],
+    ) -> UsdVendResult {
+        //Wait a max of 150 cycles (30 seconds), or longer if the USD says it needs it
+        let cycles = (self.max_response_time as u32 * 5).max(150);
+        for _ in 0..cycles {
+            for event in self.poll(bus).into_iter().flatten() {
+                match event {
+                    UsdPollEvent::VendSucceeded(item) if item == address => {
+                        defmt::debug!("USD vend success");
+                        return UsdVendResult::Succeeded(address);
+                    }
+                    UsdPollEvent::VendFailed(item, failure) if item == address => {
+                        defmt::info!("USD vend failed - {}", failure);
+                        return UsdVendResult::Failed(address, failure);
+                    }
+                    UsdPollEvent::Malfunction(code) => {
+                        defmt::error!("USD malfunction {=u8:#04x} during vend", code);
+                    }
+                    UsdPollEvent::Reinitialised(_) => {
+                        //Whatever it was doing, it isn't now
+                        return UsdVendResult::Failed(address, UsdFailure::Other(0x00));
+                    }
+                    _ => {}
+                }
+            }
+            bus.timer.delay_ms(200);
+        }
+        defmt::error!("USD did not report the result of the vend");
+        UsdVendResult::TimedOut(address)
+    }
+
+    /// Vend an item, and wait for the result
+    pub fn vend_and_wait<T: embedded_io::Write + embedded_io::Read>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        address: [u8; 2],
+    ) -> UsdVendResult {
+        if !self.vend(bus, address) {
+            defmt::error!("USD did not accept vend command");
+            return UsdVendResult::Failed(address, UsdFailure::Other(0x00));
+        }
+        self.wait_for_vend(bus, address)
+    }
+
+    /// Send the mechanism for an item back to its' home position (eg after a jam)
+    pub fn home_selection<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        address: [u8; 2],
+    ) -> bool {
+        bus.send_data_and_confirm_ack(&[
+            self.address | VEND_CMD,
+            VEND_HOME_SELECTION,
+            address[0],
+            address[1],
+        ])
+    }
+
+    /// Ask for an item's status - the reply comes back as UsdPollEvent::SelectionStatus
+    pub fn selection_status<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        address: [u8; 2],
+    ) -> bool {
+        bus.send_data_and_confirm_ack(&[
+            self.address | VEND_CMD,
+            VEND_SELECTION_STATUS,
+            address[0],
+            address[1],
+        ])
+    }
+
+    /// Send every mechanism home - UsdPollEvent::HomeComplete follows when done
+    pub fn home<T: embedded_io::Write + embedded_io::Read>(&self, bus: &mut Mdb<T>) -> bool {
+        bus.send_data_and_confirm_ack(&[self.address | CONTROL_CMD, CONTROL_HOME])
+    }
+
+    /// Move the delivery mechanism (eg a lift) to an item, ready to vend it
+    pub fn position<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        address: [u8; 2],
+    ) -> bool {
+        bus.send_data_and_confirm_ack(&[
+            self.address | CONTROL_CMD,
+            CONTROL_POSITION,
+            address[0],
+            address[1],
+        ])
+    }
+
+    /// Tell the USD the price of an item, for USDs that don't hold their own prices
+    pub fn set_price<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        address: [u8; 2],
+        price: Money,
+    ) -> bool {
+        let price = match self.to_wire(price) {
+            Some(scaled) => scaled.to_be_bytes(),
+            None => return false,
+        };
+        bus.send_data_and_confirm_ack(&[
+            self.address | EXPANSION_CMD,
+            EXPANSION_SET_PRICE,
+            address[0],
+            address[1],
+            price[0],
+            price[1],
+        ])
+    }
+
+    /// Tell the USD how much credit the customer has, for its' display
+    pub fn send_funds<T: embedded_io::Write + embedded_io::Read>(
+        &self,
+        bus: &mut Mdb<T>,
+        funds: Money,
+    ) -> bool {
+        let funds = match self.to_wire(funds) {
+            Some(scaled) => scaled.to_be_bytes(),
+            None => return false,
+        };
+        bus.send_data_and_confirm_ack(&[self.address | FUNDS_CMD, funds[0], funds[1]])
+    }
+
+    pub fn currency(&self) -> Currency {
+        Currency::from_mdb_code(self.country_code)
+    }
+
+    pub fn wire_format(&self) -> WireFormat {
+        WireFormat::new(self.currency(), self.scale_factor, self.decimal_places)
+    }
+
+    /// Convert an amount to the USD's scaled format.  None (and logged) if it is in the
+    /// wrong currency, or can't be sent exactly.
+    pub fn to_wire(&self, amount: Money) -> Option<u16> {
+        if amount.currency != self.currency() {
+            defmt::error!("{} is not in the USD's currency", amount);
+            return None;
+        }
+        match amount.to_scaled(self.wire_format()).map(u16::try_from) {
+            Some(Ok(scaled)) => Some(scaled),
+            _ => {
+                defmt::error!("{} cannot be sent to the USD", amount);
+                None
+            }
+        }
+    }
+
+    /// Convert a scaled amount from the USD
+    pub fn from_wire(&self, scaled: u16) -> Money {
+        Money::from_scaled(scaled as u32, self.wire_format())
+    }
+}
+
+//Length of each poll reply, so that chained replies can be split up
+fn poll_reply_length(reply: u8) -> usize {
+    match reply {
+        POLL_REPLY_VEND_REQUEST => 5,
+        POLL_REPLY_VEND_SUCCESS => 3,
+        POLL_REPLY_VEND_FAILED | POLL_REPLY_SELECTION_STATUS => 4,
+        POLL_REPLY_MALFUNCTION => 2,
+        POLL_REPLY_PERIPHERAL_ID => 30,
+        _ => 1,
+    }
+}